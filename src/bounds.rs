@@ -0,0 +1,65 @@
+use nalgebra::{SMatrix, SVector, Vector3};
+use crate::my_gl::{m2v, proj4_3, v2m};
+
+/// Axis-aligned box, from its smallest to its largest corner.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Aabb {
+    pub min: SVector<f32, 3>,
+    pub max: SVector<f32, 3>,
+}
+
+impl Aabb {
+    /// Smallest box holding every point of `points`, or `None` when there are
+    /// none.
+    pub fn from_points(points: impl IntoIterator<Item = SVector<f32, 3>>) -> Option<Self> {
+        points.into_iter().fold(None, |aabb, p| match aabb {
+            None => Some(Aabb { min: p, max: p }),
+            Some(Aabb { min, max }) => Some(Aabb { min: min.inf(&p), max: max.sup(&p) }),
+        })
+    }
+
+    pub fn center(&self) -> SVector<f32, 3> {
+        (self.min + self.max) / 2.
+    }
+
+    /// Length of the box along each axis.
+    pub fn size(&self) -> SVector<f32, 3> {
+        self.max - self.min
+    }
+
+    /// The eight corners of the box.
+    pub fn corners(&self) -> [SVector<f32, 3>; 8] {
+        std::array::from_fn(|i| {
+            let pick = |axis: usize| if i >> axis & 1 == 0 { self.min[axis] } else { self.max[axis] };
+            Vector3::new(pick(0), pick(1), pick(2))
+        })
+    }
+
+    /// Smallest box holding both boxes.
+    pub fn union(&self, other: &Aabb) -> Aabb {
+        Aabb { min: self.min.inf(&other.min), max: self.max.sup(&other.max) }
+    }
+
+    /// Smallest box holding this one once moved by the affine matrix `m`.
+    pub fn transformed(&self, m: &SMatrix<f32, 4, 4>) -> Aabb {
+        Aabb::from_points(self.corners().map(|p| proj4_3(m2v(m * v2m(p))))).unwrap()
+    }
+}
+
+/// Sphere holding a set of points.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct BoundingSphere {
+    pub center: SVector<f32, 3>,
+    pub radius: f32,
+}
+
+impl BoundingSphere {
+    /// Sphere around the center of the bounding box of `points`, just large
+    /// enough to hold them: not the smallest sphere, but close for most
+    /// meshes. `None` when there are no points.
+    pub fn from_points(points: impl IntoIterator<Item = SVector<f32, 3>> + Clone) -> Option<Self> {
+        let center = Aabb::from_points(points.clone())?.center();
+        let radius = points.into_iter().map(|p| (p - center).norm()).fold(0., f32::max);
+        Some(BoundingSphere { center, radius })
+    }
+}
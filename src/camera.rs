@@ -0,0 +1,253 @@
+use std::f32::consts::PI;
+use nalgebra::{SVector, SMatrix, Vector3, Vector4};
+use crate::bounds::BoundingSphere;
+use crate::my_gl::{self, ClipPlane, Pipeline, proj4_3};
+
+/// How a [`Camera`] maps what it sees to normalized device coordinates.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Projection {
+    /// Far things look smaller
+    Perspective {
+        /// Vertical field of view, in radians
+        fovy: f32,
+    },
+    /// Parallel lines stay parallel and sizes do not change with distance
+    Orthographic {
+        /// Height of the view volume, in world units
+        height: f32,
+    },
+    /// Orthographic view of the plane through `center` with depth drawn
+    /// slanting, as in technical illustrations
+    Oblique {
+        /// Height of the view volume, in world units
+        height: f32,
+        /// Direction receding lines go in on the image, in radians
+        /// counterclockwise from the right
+        angle: f32,
+        /// Length on the image of a unit of depth
+        factor: f32,
+    },
+}
+
+impl Projection {
+    /// Oblique projection keeping depths at full length.
+    pub fn cavalier(height: f32) -> Self {
+        Projection::Oblique { height, angle: PI / 4., factor: 1. }
+    }
+
+    /// Oblique projection halving depths, which looks more natural.
+    pub fn cabinet(height: f32) -> Self {
+        Projection::Oblique { height, angle: PI / 4., factor: 0.5 }
+    }
+}
+
+/// A ray of world space, e.g. to pick what is under a pixel.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Ray {
+    pub origin: SVector<f32, 3>,
+    /// Unit direction
+    pub direction: SVector<f32, 3>,
+}
+
+impl Ray {
+    /// Point at distance `t` along the ray.
+    pub fn at(&self, t: f32) -> SVector<f32, 3> {
+        self.origin + self.direction * t
+    }
+}
+
+/// A camera at `eye` looking at `center`, with its projection.
+///
+/// Its matrices work with [`my_gl::viewport`]: `viewport * projection * view`
+/// takes world space to the screen, with depth `0` on the near plane and `1`
+/// on the far plane.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Camera {
+    pub eye: SVector<f32, 3>,
+    pub center: SVector<f32, 3>,
+    /// Up direction, which need not be orthogonal to the view
+    pub up: SVector<f32, 3>,
+    pub projection: Projection,
+    /// Width over height of the image
+    pub aspect: f32,
+    /// Distance from `eye` to the nearest plane that is drawn
+    pub near: f32,
+    /// Distance from `eye` to the farthest plane that is drawn
+    pub far: f32,
+}
+
+impl Camera {
+    /// Camera at `eye` looking at `center` with a 45° perspective, a square
+    /// image and clip planes `0.1` and `100` away.
+    pub fn new(eye: SVector<f32, 3>, center: SVector<f32, 3>, up: SVector<f32, 3>) -> Self {
+        Camera {
+            eye,
+            center,
+            up,
+            projection: Projection::Perspective { fovy: PI / 4. },
+            aspect: 1.,
+            near: 0.1,
+            far: 100.,
+        }
+    }
+
+    /// Orthographic camera looking at `center` from `distance` away, from
+    /// `azimuth` radians around the `y` axis (counterclockwise from `z`) and
+    /// `elevation` radians above the `xz` plane, seeing `height` units high.
+    pub fn axonometric(center: SVector<f32, 3>, distance: f32, azimuth: f32, elevation: f32, height: f32) -> Self {
+        let direction = Vector3::new(
+            elevation.cos() * azimuth.sin(),
+            elevation.sin(),
+            elevation.cos() * azimuth.cos(),
+        );
+        Camera {
+            projection: Projection::Orthographic { height },
+            ..Camera::new(center + direction * distance, center, Vector3::y())
+        }
+    }
+
+    /// Axonometric camera foreshortening the three axes equally.
+    pub fn isometric(center: SVector<f32, 3>, distance: f32, height: f32) -> Self {
+        Camera::axonometric(center, distance, PI / 4., (1. / 2f32.sqrt()).atan(), height)
+    }
+
+    /// Axonometric camera foreshortening the horizontal axes equally and
+    /// less than the vertical one, with the 2:1 slopes of pixel art.
+    pub fn dimetric(center: SVector<f32, 3>, distance: f32, height: f32) -> Self {
+        Camera::axonometric(center, distance, PI / 4., PI / 6., height)
+    }
+
+    /// Unit direction the camera looks in.
+    pub fn forward(&self) -> SVector<f32, 3> {
+        (self.center - self.eye).normalize()
+    }
+
+    /// Takes world space to view space, where the camera is at the origin
+    /// looking down `-z` with `y` up.
+    pub fn view(&self) -> SMatrix<f32, 4, 4> {
+        let z: SVector<f32, 3> = (self.eye - self.center).normalize();
+        let x: SVector<f32, 3> = self.up.cross(&z).normalize();
+        let y: SVector<f32, 3> = z.cross(&x);
+        let mut res: SMatrix<f32, 4, 4> = SMatrix::identity();
+        for (row, axis) in [x, y, z].iter().enumerate() {
+            for i in 0..3 {
+                res[(row, i)] = axis[i];
+            }
+            res[(row, 3)] = -axis.dot(&self.eye);
+        }
+        res
+    }
+
+    /// Takes view space to clip space.
+    pub fn projection_matrix(&self) -> SMatrix<f32, 4, 4> {
+        match self.projection {
+            Projection::Perspective { fovy } => my_gl::perspective(fovy, self.aspect, self.near, self.far),
+            Projection::Orthographic { height } => my_gl::orthographic(height * self.aspect, height, self.near, self.far),
+            Projection::Oblique { height, angle, factor } => {
+                let focus = (self.center - self.eye).norm();
+                my_gl::orthographic(height * self.aspect, height, self.near, self.far) * my_gl::oblique(angle, factor, focus)
+            }
+        }
+    }
+
+    /// Takes world space to clip space: the projection-modelview matrix of
+    /// the shaders for a model placed in the world as is.
+    pub fn view_projection(&self) -> SMatrix<f32, 4, 4> {
+        self.projection_matrix() * self.view()
+    }
+
+    /// Pipeline drawing through `viewport` what lies between the near and
+    /// far planes of the camera.
+    pub fn pipeline(&self, viewport: SMatrix<f32, 4, 4>) -> Pipeline {
+        let [.., far, near] = ClipPlane::frustum();
+        Pipeline { clip_planes: vec![near, far], ..Pipeline::new(viewport) }
+    }
+
+    /// Ray from the near plane through the point at normalized device
+    /// coordinates `ndc`, `-1..1` from the bottom left of the image, or
+    /// `None` when the view volume is flat.
+    pub fn ray(&self, ndc: SVector<f32, 2>) -> Option<Ray> {
+        let inverse = self.view_projection().try_inverse()?;
+        let unproject = |z: f32| {
+            let p = inverse * Vector4::new(ndc.x, ndc.y, z, 1.);
+            proj4_3(p) / p.w
+        };
+        let (near, far) = (unproject(1.), unproject(-1.));
+        Some(Ray { origin: near, direction: (far - near).try_normalize(f32::EPSILON)? })
+    }
+
+    /// Ray through the screen point (`x`, `y`) of `viewport`, e.g. the
+    /// center of a pixel at (`x + 0.5`, `y + 0.5`), or `None` when the view
+    /// volume or the viewport is flat.
+    pub fn ray_through(&self, viewport: &SMatrix<f32, 4, 4>, x: f32, y: f32) -> Option<Ray> {
+        let ndc = viewport.try_inverse()? * Vector4::new(x, y, 0.5, 1.);
+        self.ray(ndc.xy())
+    }
+
+    /// Same camera framing `sphere`: looking at its center from the same
+    /// direction, just far enough, or with a view volume just large enough,
+    /// for it to fill the narrower side of the image, and with the clip
+    /// planes around it.
+    pub fn fit(&self, sphere: &BoundingSphere) -> Self {
+        let radius = sphere.radius.max(f32::EPSILON);
+        // Parallel projections see the same from any distance
+        let (projection, distance) = match self.projection {
+            Projection::Perspective { fovy } => {
+                let tan = (fovy / 2.).tan();
+                let half_angle = (tan * self.aspect.min(1.)).atan();
+                (self.projection, radius / half_angle.sin())
+            }
+            Projection::Orthographic { .. } => {
+                (Projection::Orthographic { height: 2. * radius / self.aspect.min(1.) }, 2. * radius)
+            }
+            Projection::Oblique { angle, factor, .. } => {
+                // The shear draws the sphere as an ellipse, longer along `angle`
+                let half_width = radius * (1. + (factor * angle.cos()).powi(2)).sqrt();
+                let half_height = radius * (1. + (factor * angle.sin()).powi(2)).sqrt();
+                let height = 2. * half_height.max(half_width / self.aspect);
+                (Projection::Oblique { height, angle, factor }, 2. * radius)
+            }
+        };
+        // Keeps the surface of the sphere off the clip planes
+        let margin = radius * 1.01;
+        Camera {
+            eye: sphere.center - self.forward() * distance,
+            center: sphere.center,
+            projection,
+            near: (distance - margin).max(distance * 1e-3),
+            far: distance + margin,
+            ..*self
+        }
+    }
+
+    /// Screen pixels per unit of viewport depth for things `distance` in
+    /// front of the camera, with a viewport `height` pixels high: the
+    /// `depth_scale` of [`crate::ssao::Ssao`].
+    pub fn depth_scale(&self, distance: f32, height: f32) -> f32 {
+        let proj = self.projection_matrix();
+        let (z, w) = (proj[(2, 3)] - proj[(2, 2)] * distance, proj[(3, 3)] - proj[(3, 2)] * distance);
+        let pixels_per_unit = height / 2. * proj[(1, 1)] / w;
+        // Viewport depth is `(1 - z / w) / 2`
+        let depth_per_unit = ((proj[(2, 2)] * w - z * proj[(3, 2)]) / (2. * w * w)).abs();
+        pixels_per_unit / depth_per_unit
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rays_through_the_center_look_at_it() {
+        let camera = Camera::new(Vector3::new(1., 2., 3.), Vector3::zeros(), Vector3::y());
+        let viewport = my_gl::viewport(0., 0., 100., 100.);
+        let ray = camera.ray_through(&viewport, 50., 50.).unwrap();
+        assert!((ray.direction + camera.eye.normalize()).norm() < 1e-4, "{ray:?}");
+    }
+
+    #[test]
+    fn flat_viewports_have_no_rays() {
+        let camera = Camera::new(Vector3::new(1., 2., 3.), Vector3::zeros(), Vector3::y());
+        assert!(camera.ray_through(&my_gl::viewport(0., 0., 0., 100.), 0., 50.).is_none());
+    }
+}
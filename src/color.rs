@@ -0,0 +1,291 @@
+use std::iter::Sum;
+use std::ops::{Add, AddAssign, Div, DivAssign, Mul, MulAssign, Neg, Sub, SubAssign};
+use std::sync::OnceLock;
+use image::{Rgb, Rgba};
+use nalgebra::{SVector, Vector4};
+
+/// Encoding of the channels of an 8-bit image.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ColorSpace {
+    /// Values proportional to light, or data such as normals
+    Linear,
+    /// Colors gamma-encoded for display, as in most image files
+    Srgb,
+}
+
+/// Linear light of the sRGB-encoded value `c`, both in `0..1`.
+pub fn srgb_to_linear(c: f32) -> f32 {
+    if c <= 0.04045 { c / 12.92 } else { ((c + 0.055) / 1.055).powf(2.4) }
+}
+
+/// sRGB encoding of the linear light `c`, both in `0..1`.
+pub fn linear_to_srgb(c: f32) -> f32 {
+    if c <= 0.0031308 { c * 12.92 } else { 1.055 * c.powf(1. / 2.4) - 0.055 }
+}
+
+/// Linear light of the 8-bit sRGB channel `c`.
+pub fn srgb8_to_linear(c: u8) -> f32 {
+    static TABLE: OnceLock<[f32; 256]> = OnceLock::new();
+    TABLE.get_or_init(|| std::array::from_fn(|i| srgb_to_linear(i as f32 / 255.)))[c as usize]
+}
+
+/// 8-bit sRGB channel of the linear light `c`, saturating outside `0..1`.
+pub fn linear_to_srgb8(c: f32) -> u8 {
+    (linear_to_srgb(c.clamp(0., 1.)) * 255.).round() as u8
+}
+
+/// `0..1` value of an 8-bit channel, saturating.
+fn unorm8(c: f32) -> u8 {
+    (c * 255.).round().clamp(0., 255.) as u8
+}
+
+/// An RGBA color with float channels, usually in linear light.
+///
+/// Arithmetic is componentwise over all four channels, alpha included, as
+/// for a vector; channels may leave `0..1`, e.g. for high dynamic range
+/// lighting, until [`Color::clamp`]ed or converted to 8 bits.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Color {
+    pub r: f32,
+    pub g: f32,
+    pub b: f32,
+    pub a: f32,
+}
+
+impl Color {
+    pub const BLACK: Color = Color::rgb(0., 0., 0.);
+    pub const WHITE: Color = Color::rgb(1., 1., 1.);
+    pub const TRANSPARENT: Color = Color::new(0., 0., 0., 0.);
+
+    pub const fn new(r: f32, g: f32, b: f32, a: f32) -> Self {
+        Color { r, g, b, a }
+    }
+
+    /// Opaque color.
+    pub const fn rgb(r: f32, g: f32, b: f32) -> Self {
+        Color::new(r, g, b, 1.)
+    }
+
+    /// Opaque gray of intensity `v`.
+    pub const fn gray(v: f32) -> Self {
+        Color::rgb(v, v, v)
+    }
+
+    /// Same color with alpha `a`.
+    pub fn with_alpha(self, a: f32) -> Self {
+        Color { a, ..self }
+    }
+
+    /// Applies `f` to every channel.
+    pub fn map(self, f: impl Fn(f32) -> f32) -> Self {
+        Color::new(f(self.r), f(self.g), f(self.b), f(self.a))
+    }
+
+    /// Color `t` of the way from `self` to `other`.
+    pub fn lerp(self, other: Color, t: f32) -> Self {
+        self + (other - self) * t
+    }
+
+    /// Color with every channel clamped to `0..1`.
+    pub fn clamp(self) -> Self {
+        self.map(|c| c.clamp(0., 1.))
+    }
+
+    /// Relative luminance of the linear color, by the Rec. 709 weights.
+    pub fn luminance(self) -> f32 {
+        0.2126 * self.r + 0.7152 * self.g + 0.0722 * self.b
+    }
+
+    /// Color with its RGB multiplied by its alpha.
+    pub fn premultiply(self) -> Self {
+        Color::new(self.r * self.a, self.g * self.a, self.b * self.a, self.a)
+    }
+
+    /// Straight-alpha color of the premultiplied color `self`; fully
+    /// transparent colors become transparent black.
+    pub fn unpremultiply(self) -> Self {
+        if self.a == 0. {
+            return Color::TRANSPARENT;
+        }
+        Color::new(self.r / self.a, self.g / self.a, self.b / self.a, self.a)
+    }
+
+    /// Premultiplied color `self` composited over the premultiplied `below`.
+    pub fn over(self, below: Color) -> Self {
+        self + below * (1. - self.a)
+    }
+
+    /// Opaque color of an sRGB-encoded 8-bit pixel, in linear light.
+    pub fn from_srgb8(rgb: Rgb<u8>) -> Self {
+        let [r, g, b] = rgb.0.map(srgb8_to_linear);
+        Color::rgb(r, g, b)
+    }
+
+    /// sRGB-encoded 8-bit pixel of the linear color, saturating and dropping alpha.
+    pub fn to_srgb8(self) -> Rgb<u8> {
+        Rgb([self.r, self.g, self.b].map(linear_to_srgb8))
+    }
+
+    /// Color of an sRGB-encoded 8-bit pixel with linear alpha, in linear light.
+    pub fn from_srgba8(rgba: Rgba<u8>) -> Self {
+        let [r, g, b, a] = rgba.0;
+        Color::new(srgb8_to_linear(r), srgb8_to_linear(g), srgb8_to_linear(b), a as f32 / 255.)
+    }
+
+    /// sRGB-encoded 8-bit pixel of the linear color, with linear alpha, saturating.
+    pub fn to_srgba8(self) -> Rgba<u8> {
+        let [r, g, b] = [self.r, self.g, self.b].map(linear_to_srgb8);
+        Rgba([r, g, b, unorm8(self.a)])
+    }
+
+    /// 8-bit pixel of the color, saturating and dropping alpha.
+    pub fn to_rgb8(self) -> Rgb<u8> {
+        Rgb([self.r, self.g, self.b].map(unorm8))
+    }
+
+    /// 8-bit pixel of the color, saturating.
+    pub fn to_rgba8(self) -> Rgba<u8> {
+        Rgba([self.r, self.g, self.b, self.a].map(unorm8))
+    }
+}
+
+/// Opaque color of the pixel, channels scaled to `0..1` without decoding.
+impl From<Rgb<u8>> for Color {
+    fn from(rgb: Rgb<u8>) -> Self {
+        let [r, g, b] = rgb.0.map(|c| c as f32 / 255.);
+        Color::rgb(r, g, b)
+    }
+}
+
+/// Color of the pixel, channels scaled to `0..1` without decoding.
+impl From<Rgba<u8>> for Color {
+    fn from(rgba: Rgba<u8>) -> Self {
+        let [r, g, b, a] = rgba.0.map(|c| c as f32 / 255.);
+        Color::new(r, g, b, a)
+    }
+}
+
+/// Opaque color of an RGB vector.
+impl From<SVector<f32, 3>> for Color {
+    fn from(v: SVector<f32, 3>) -> Self {
+        Color::rgb(v.x, v.y, v.z)
+    }
+}
+
+impl From<SVector<f32, 4>> for Color {
+    fn from(v: SVector<f32, 4>) -> Self {
+        Color::new(v.x, v.y, v.z, v.w)
+    }
+}
+
+impl From<Color> for SVector<f32, 4> {
+    fn from(c: Color) -> Self {
+        Vector4::new(c.r, c.g, c.b, c.a)
+    }
+}
+
+impl Add for Color {
+    type Output = Color;
+
+    fn add(self, rhs: Color) -> Color {
+        Color::new(self.r + rhs.r, self.g + rhs.g, self.b + rhs.b, self.a + rhs.a)
+    }
+}
+
+impl Sub for Color {
+    type Output = Color;
+
+    fn sub(self, rhs: Color) -> Color {
+        Color::new(self.r - rhs.r, self.g - rhs.g, self.b - rhs.b, self.a - rhs.a)
+    }
+}
+
+impl Mul for Color {
+    type Output = Color;
+
+    fn mul(self, rhs: Color) -> Color {
+        Color::new(self.r * rhs.r, self.g * rhs.g, self.b * rhs.b, self.a * rhs.a)
+    }
+}
+
+impl Mul<f32> for Color {
+    type Output = Color;
+
+    fn mul(self, rhs: f32) -> Color {
+        self.map(|c| c * rhs)
+    }
+}
+
+impl Mul<Color> for f32 {
+    type Output = Color;
+
+    fn mul(self, rhs: Color) -> Color {
+        rhs * self
+    }
+}
+
+impl Div for Color {
+    type Output = Color;
+
+    fn div(self, rhs: Color) -> Color {
+        Color::new(self.r / rhs.r, self.g / rhs.g, self.b / rhs.b, self.a / rhs.a)
+    }
+}
+
+impl Div<f32> for Color {
+    type Output = Color;
+
+    fn div(self, rhs: f32) -> Color {
+        self.map(|c| c / rhs)
+    }
+}
+
+impl Neg for Color {
+    type Output = Color;
+
+    fn neg(self) -> Color {
+        self.map(|c| -c)
+    }
+}
+
+impl AddAssign for Color {
+    fn add_assign(&mut self, rhs: Color) {
+        *self = *self + rhs;
+    }
+}
+
+impl SubAssign for Color {
+    fn sub_assign(&mut self, rhs: Color) {
+        *self = *self - rhs;
+    }
+}
+
+impl MulAssign for Color {
+    fn mul_assign(&mut self, rhs: Color) {
+        *self = *self * rhs;
+    }
+}
+
+impl MulAssign<f32> for Color {
+    fn mul_assign(&mut self, rhs: f32) {
+        *self = *self * rhs;
+    }
+}
+
+impl DivAssign for Color {
+    fn div_assign(&mut self, rhs: Color) {
+        *self = *self / rhs;
+    }
+}
+
+impl DivAssign<f32> for Color {
+    fn div_assign(&mut self, rhs: f32) {
+        *self = *self / rhs;
+    }
+}
+
+impl Sum for Color {
+    fn sum<I: Iterator<Item = Color>>(iter: I) -> Color {
+        iter.fold(Color::TRANSPARENT, |sum, c| sum + c)
+    }
+}
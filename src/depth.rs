@@ -0,0 +1,338 @@
+/// Test a fragment must pass against the depth or stencil value already
+/// stored under it.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CompareFunc {
+    Never,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    Always,
+}
+
+impl CompareFunc {
+    /// Whether a fragment with `value` passes over the `stored` one.
+    pub fn passes(self, value: f32, stored: f32) -> bool {
+        match self {
+            CompareFunc::Never => false,
+            CompareFunc::Less => value < stored,
+            CompareFunc::LessEqual => value <= stored,
+            CompareFunc::Greater => value > stored,
+            CompareFunc::GreaterEqual => value >= stored,
+            CompareFunc::Always => true,
+        }
+    }
+
+    /// The same test with the depth axis flipped, for a reversed depth range.
+    pub fn reversed(self) -> Self {
+        match self {
+            CompareFunc::Less => CompareFunc::Greater,
+            CompareFunc::LessEqual => CompareFunc::GreaterEqual,
+            CompareFunc::Greater => CompareFunc::Less,
+            CompareFunc::GreaterEqual => CompareFunc::LessEqual,
+            f => f,
+        }
+    }
+}
+
+/// Full-precision depth of every pixel of an image, with the state of the
+/// depth test.
+///
+/// The viewport maps normalized device depth to `0` at the near side and `1`
+/// at the far side; the depth buffer then maps that to its `near`..`far`
+/// range. A reversed range (`near > far`, see [`DepthBuffer::reversed`])
+/// stores nearer fragments as greater depths and flips the compare function
+/// to match. It only changes the direction of the test: the viewport depth is
+/// computed first, so it does not gain any precision.
+#[derive(Clone, Debug)]
+pub struct DepthBuffer {
+    width: u32,
+    height: u32,
+    data: Vec<f32>,
+    /// Stored depth of the near side of the view volume
+    pub near: f32,
+    /// Stored depth of the far side of the view volume, also the clear value
+    pub far: f32,
+    /// Test fragments must pass to be drawn
+    pub func: CompareFunc,
+    /// Whether drawn fragments store their depth
+    pub write: bool,
+}
+
+impl DepthBuffer {
+    /// Depth buffer mapping depth to `0..1`, drawing the nearest fragments.
+    pub fn new(width: u32, height: u32) -> Self {
+        DepthBuffer {
+            width,
+            height,
+            data: vec![1.; (width * height) as usize],
+            near: 0.,
+            far: 1.,
+            func: CompareFunc::Less,
+            write: true,
+        }
+    }
+
+    /// Depth buffer mapping depth to `1..0`, drawing the nearest fragments.
+    pub fn reversed(width: u32, height: u32) -> Self {
+        let mut depth = DepthBuffer::new(width, height);
+        depth.set_reversed(true);
+        depth
+    }
+
+    /// Orders the depth range so that it is reversed or not, flipping the
+    /// compare function along with it, and clears the buffer.
+    pub fn set_reversed(&mut self, reversed: bool) {
+        if self.is_reversed() != reversed {
+            std::mem::swap(&mut self.near, &mut self.far);
+            self.func = self.func.reversed();
+        }
+        self.clear();
+    }
+
+    /// Whether `near` is stored as a greater depth than `far`.
+    pub fn is_reversed(&self) -> bool {
+        self.near > self.far
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// Resets every pixel to the far depth.
+    pub fn clear(&mut self) {
+        self.data.fill(self.far);
+    }
+
+    /// Stored depth of the pixel at (`x`, `y`).
+    pub fn get(&self, x: u32, y: u32) -> f32 {
+        self.data[(y * self.width + x) as usize]
+    }
+
+    /// Depth values row by row.
+    pub fn as_slice(&self) -> &[f32] {
+        &self.data
+    }
+
+    /// Maps a viewport depth in `0..1` to the depth range, clamping it first.
+    pub fn window_depth(&self, z: f32) -> f32 {
+        self.near + (self.far - self.near) * z.clamp(0., 1.)
+    }
+
+    /// Depth-tests a fragment at `depth` on the pixel at (`x`, `y`).
+    pub fn test(&self, x: u32, y: u32, depth: f32) -> bool {
+        self.func.passes(depth, self.get(x, y))
+    }
+
+    /// Stores the depth of a drawn fragment, unless depth writes are disabled.
+    pub fn update(&mut self, x: u32, y: u32, depth: f32) {
+        if self.write {
+            self.data[(y * self.width + x) as usize] = depth;
+        }
+    }
+
+    /// Copy of the `width` x `height` region at (`x`, `y`), with the same state.
+    pub fn region(&self, x: u32, y: u32, width: u32, height: u32) -> Self {
+        let mut data = Vec::with_capacity((width * height) as usize);
+        for row in y..y + height {
+            let start = (row * self.width + x) as usize;
+            data.extend_from_slice(&self.data[start..start + width as usize]);
+        }
+        DepthBuffer { width, height, data, ..*self }
+    }
+
+    /// Copies the depths of `region` to (`x`, `y`), see [`DepthBuffer::region`].
+    pub fn copy_from(&mut self, region: &DepthBuffer, x: u32, y: u32) {
+        let width = region.width as usize;
+        for (row, depths) in region.data.chunks(width).enumerate() {
+            let start = (y as usize + row) * self.width as usize + x as usize;
+            self.data[start..start + width].copy_from_slice(depths);
+        }
+    }
+}
+
+/// Update of a stencil value, after a fragment is tested.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum StencilOp {
+    Keep,
+    Zero,
+    /// Stores the reference value
+    Replace,
+    /// Increments, saturating at 255
+    Increment,
+    /// Decrements, saturating at 0
+    Decrement,
+    Invert,
+}
+
+/// 8-bit stencil value of every pixel of an image, with the state of the
+/// stencil test.
+///
+/// A fragment passes when `func` holds between `reference` and the stored
+/// value. The stored value is then updated with `fail` if the fragment failed
+/// the stencil test, `depth_fail` if it failed the depth test, and `pass`
+/// otherwise. Discarded fragments leave it untouched.
+#[derive(Clone, Debug)]
+pub struct StencilBuffer {
+    width: u32,
+    height: u32,
+    data: Vec<u8>,
+    pub func: CompareFunc,
+    pub reference: u8,
+    pub fail: StencilOp,
+    pub depth_fail: StencilOp,
+    pub pass: StencilOp,
+}
+
+impl StencilBuffer {
+    /// Stencil buffer cleared to zero, passing and keeping every fragment.
+    pub fn new(width: u32, height: u32) -> Self {
+        StencilBuffer {
+            width,
+            height,
+            data: vec![0; (width * height) as usize],
+            func: CompareFunc::Always,
+            reference: 0,
+            fail: StencilOp::Keep,
+            depth_fail: StencilOp::Keep,
+            pass: StencilOp::Keep,
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// Resets every pixel to `value`.
+    pub fn clear(&mut self, value: u8) {
+        self.data.fill(value);
+    }
+
+    /// Stored value of the pixel at (`x`, `y`).
+    pub fn get(&self, x: u32, y: u32) -> u8 {
+        self.data[(y * self.width + x) as usize]
+    }
+
+    /// Values row by row.
+    pub fn as_slice(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Stencil-tests a fragment on the pixel at (`x`, `y`).
+    pub fn test(&self, x: u32, y: u32) -> bool {
+        self.func.passes(self.reference as f32, self.get(x, y) as f32)
+    }
+
+    /// Updates the value of the pixel at (`x`, `y`) with `op`.
+    pub fn apply(&mut self, x: u32, y: u32, op: StencilOp) {
+        let value = &mut self.data[(y * self.width + x) as usize];
+        *value = match op {
+            StencilOp::Keep => *value,
+            StencilOp::Zero => 0,
+            StencilOp::Replace => self.reference,
+            StencilOp::Increment => value.saturating_add(1),
+            StencilOp::Decrement => value.saturating_sub(1),
+            StencilOp::Invert => !*value,
+        };
+    }
+
+    /// Copy of the `width` x `height` region at (`x`, `y`), with the same state.
+    pub fn region(&self, x: u32, y: u32, width: u32, height: u32) -> Self {
+        let mut data = Vec::with_capacity((width * height) as usize);
+        for row in y..y + height {
+            let start = (row * self.width + x) as usize;
+            data.extend_from_slice(&self.data[start..start + width as usize]);
+        }
+        StencilBuffer { width, height, data, ..*self }
+    }
+
+    /// Copies the values of `region` to (`x`, `y`), see [`StencilBuffer::region`].
+    pub fn copy_from(&mut self, region: &StencilBuffer, x: u32, y: u32) {
+        let width = region.width as usize;
+        for (row, values) in region.data.chunks(width).enumerate() {
+            let start = (y as usize + row) * self.width as usize + x as usize;
+            self.data[start..start + width].copy_from_slice(values);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FUNCS: [CompareFunc; 6] = [
+        CompareFunc::Never,
+        CompareFunc::Less,
+        CompareFunc::LessEqual,
+        CompareFunc::Greater,
+        CompareFunc::GreaterEqual,
+        CompareFunc::Always,
+    ];
+
+    #[test]
+    fn compare_funcs() {
+        // Whether each function passes a lower, equal and greater value
+        let expected = [
+            [false, false, false],
+            [true, false, false],
+            [true, true, false],
+            [false, false, true],
+            [false, true, true],
+            [true, true, true],
+        ];
+        for (func, expected) in FUNCS.into_iter().zip(expected) {
+            assert_eq!([0.25, 0.5, 0.75].map(|value| func.passes(value, 0.5)), expected, "{func:?}");
+        }
+    }
+
+    #[test]
+    fn reversed_compare_funcs_swap_their_operands() {
+        for func in FUNCS {
+            for (value, stored) in [(0.25, 0.5), (0.5, 0.5), (0.75, 0.5)] {
+                assert_eq!(func.reversed().passes(value, stored), func.passes(stored, value), "{func:?}");
+            }
+            assert_eq!(func.reversed().reversed(), func);
+        }
+    }
+
+    /// Whether each fragment of `depths`, in viewport depth, is drawn on a
+    /// single pixel of `buffer`.
+    fn draw(buffer: &mut DepthBuffer, depths: &[f32]) -> Vec<bool> {
+        depths.iter().map(|&z| {
+            let depth = buffer.window_depth(z);
+            let pass = buffer.test(0, 0, depth);
+            if pass {
+                buffer.update(0, 0, depth);
+            }
+            pass
+        }).collect()
+    }
+
+    #[test]
+    fn reversed_buffers_draw_the_nearest_fragments() {
+        let mut depth = DepthBuffer::reversed(1, 1);
+        assert!(depth.is_reversed());
+        assert_eq!((depth.near, depth.far, depth.func), (1., 0., CompareFunc::Greater));
+        // Cleared to the far depth
+        assert_eq!(depth.as_slice(), &[0.]);
+        assert_eq!((depth.window_depth(0.), depth.window_depth(1.)), (1., 0.));
+
+        let depths = [0.6, 0.3, 0.8, 0.3, 0.1];
+        let expected = [true, true, false, false, true];
+        assert_eq!(draw(&mut depth, &depths), expected);
+        assert_eq!(depth.as_slice(), &[0.9]);
+        assert_eq!(draw(&mut DepthBuffer::new(1, 1), &depths), expected);
+
+        depth.set_reversed(false);
+        assert_eq!((depth.near, depth.far, depth.func), (0., 1., CompareFunc::Less));
+        assert_eq!(depth.as_slice(), &[1.]);
+    }
+}
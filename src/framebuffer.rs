@@ -0,0 +1,268 @@
+use image::{imageops, GenericImage, ImageBuffer, Luma, Rgb, Rgb32FImage, RgbImage, Rgba, Rgba32FImage, RgbaImage};
+use crate::color::Color;
+use crate::depth::{DepthBuffer, StencilBuffer};
+
+/// Most color attachments a framebuffer can have, and outputs a fragment
+/// shader can write.
+pub const MAX_COLOR_ATTACHMENTS: usize = 8;
+
+/// Pixel format of a color attachment.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ColorFormat {
+    /// Single float channel
+    R32F,
+    Rgb8,
+    Rgba8,
+    /// Linear color stored sRGB-encoded, for display
+    Srgb8,
+    /// Linear color stored sRGB-encoded, with linear alpha
+    Srgba8,
+    /// High dynamic range color
+    Rgb32F,
+    Rgba32F,
+}
+
+/// A color image a framebuffer renders into.
+///
+/// Values are read and written as [`Color`]s whatever the format: 8-bit
+/// channels store `0..1` clamped and rounded, sRGB ones encoding the color
+/// on write and decoding it on read, float channels store any value, and
+/// channels a format lacks are dropped (alpha reads as `1`).
+#[derive(Clone, Debug)]
+pub enum ColorAttachment {
+    R32F(ImageBuffer<Luma<f32>, Vec<f32>>),
+    Rgb8(RgbImage),
+    Rgba8(RgbaImage),
+    Srgb8(RgbImage),
+    Srgba8(RgbaImage),
+    Rgb32F(Rgb32FImage),
+    Rgba32F(Rgba32FImage),
+}
+
+impl ColorAttachment {
+    /// Black image of the given format and size.
+    pub fn new(format: ColorFormat, width: u32, height: u32) -> Self {
+        match format {
+            ColorFormat::R32F => ColorAttachment::R32F(ImageBuffer::new(width, height)),
+            ColorFormat::Rgb8 => ColorAttachment::Rgb8(ImageBuffer::new(width, height)),
+            ColorFormat::Rgba8 => ColorAttachment::Rgba8(ImageBuffer::new(width, height)),
+            ColorFormat::Srgb8 => ColorAttachment::Srgb8(ImageBuffer::new(width, height)),
+            ColorFormat::Srgba8 => ColorAttachment::Srgba8(ImageBuffer::new(width, height)),
+            ColorFormat::Rgb32F => ColorAttachment::Rgb32F(ImageBuffer::new(width, height)),
+            ColorFormat::Rgba32F => ColorAttachment::Rgba32F(ImageBuffer::new(width, height)),
+        }
+    }
+
+    pub fn format(&self) -> ColorFormat {
+        match self {
+            ColorAttachment::R32F(_) => ColorFormat::R32F,
+            ColorAttachment::Rgb8(_) => ColorFormat::Rgb8,
+            ColorAttachment::Rgba8(_) => ColorFormat::Rgba8,
+            ColorAttachment::Srgb8(_) => ColorFormat::Srgb8,
+            ColorAttachment::Srgba8(_) => ColorFormat::Srgba8,
+            ColorAttachment::Rgb32F(_) => ColorFormat::Rgb32F,
+            ColorAttachment::Rgba32F(_) => ColorFormat::Rgba32F,
+        }
+    }
+
+    pub fn dimensions(&self) -> (u32, u32) {
+        match self {
+            ColorAttachment::R32F(i) => i.dimensions(),
+            ColorAttachment::Rgb8(i) => i.dimensions(),
+            ColorAttachment::Rgba8(i) => i.dimensions(),
+            ColorAttachment::Srgb8(i) => i.dimensions(),
+            ColorAttachment::Srgba8(i) => i.dimensions(),
+            ColorAttachment::Rgb32F(i) => i.dimensions(),
+            ColorAttachment::Rgba32F(i) => i.dimensions(),
+        }
+    }
+
+    /// Color of the pixel at (`x`, `y`).
+    pub fn get(&self, x: u32, y: u32) -> Color {
+        match self {
+            ColorAttachment::R32F(i) => Color::rgb(i.get_pixel(x, y).0[0], 0., 0.),
+            ColorAttachment::Rgb8(i) => Color::from(*i.get_pixel(x, y)),
+            ColorAttachment::Rgba8(i) => Color::from(*i.get_pixel(x, y)),
+            ColorAttachment::Srgb8(i) => Color::from_srgb8(*i.get_pixel(x, y)),
+            ColorAttachment::Srgba8(i) => Color::from_srgba8(*i.get_pixel(x, y)),
+            ColorAttachment::Rgb32F(i) => {
+                let [r, g, b] = i.get_pixel(x, y).0;
+                Color::rgb(r, g, b)
+            }
+            ColorAttachment::Rgba32F(i) => {
+                let [r, g, b, a] = i.get_pixel(x, y).0;
+                Color::new(r, g, b, a)
+            }
+        }
+    }
+
+    /// Stores `color` in the pixel at (`x`, `y`).
+    pub fn put(&mut self, x: u32, y: u32, color: Color) {
+        match self {
+            ColorAttachment::R32F(i) => i.put_pixel(x, y, Luma([color.r])),
+            ColorAttachment::Rgb8(i) => i.put_pixel(x, y, color.to_rgb8()),
+            ColorAttachment::Rgba8(i) => i.put_pixel(x, y, color.to_rgba8()),
+            ColorAttachment::Srgb8(i) => i.put_pixel(x, y, color.to_srgb8()),
+            ColorAttachment::Srgba8(i) => i.put_pixel(x, y, color.to_srgba8()),
+            ColorAttachment::Rgb32F(i) => i.put_pixel(x, y, Rgb([color.r, color.g, color.b])),
+            ColorAttachment::Rgba32F(i) => i.put_pixel(x, y, Rgba([color.r, color.g, color.b, color.a])),
+        }
+    }
+
+    /// 8-bit RGB copy of the attachment, e.g. to save it. sRGB attachments
+    /// keep their encoding, the others are copied as is.
+    pub fn to_rgb8(&self) -> RgbImage {
+        match self {
+            ColorAttachment::Rgb8(i) | ColorAttachment::Srgb8(i) => i.clone(),
+            ColorAttachment::Srgba8(i) => ImageBuffer::from_fn(i.width(), i.height(), |x, y| {
+                let [r, g, b, _] = i.get_pixel(x, y).0;
+                Rgb([r, g, b])
+            }),
+            _ => {
+                let (width, height) = self.dimensions();
+                ImageBuffer::from_fn(width, height, |x, y| self.get(x, y).to_rgb8())
+            }
+        }
+    }
+
+    /// Copy of the `width` x `height` region at (`x`, `y`).
+    pub fn region(&self, x: u32, y: u32, width: u32, height: u32) -> Self {
+        match self {
+            ColorAttachment::R32F(i) => ColorAttachment::R32F(imageops::crop_imm(i, x, y, width, height).to_image()),
+            ColorAttachment::Rgb8(i) => ColorAttachment::Rgb8(imageops::crop_imm(i, x, y, width, height).to_image()),
+            ColorAttachment::Rgba8(i) => ColorAttachment::Rgba8(imageops::crop_imm(i, x, y, width, height).to_image()),
+            ColorAttachment::Srgb8(i) => ColorAttachment::Srgb8(imageops::crop_imm(i, x, y, width, height).to_image()),
+            ColorAttachment::Srgba8(i) => ColorAttachment::Srgba8(imageops::crop_imm(i, x, y, width, height).to_image()),
+            ColorAttachment::Rgb32F(i) => ColorAttachment::Rgb32F(imageops::crop_imm(i, x, y, width, height).to_image()),
+            ColorAttachment::Rgba32F(i) => ColorAttachment::Rgba32F(imageops::crop_imm(i, x, y, width, height).to_image()),
+        }
+    }
+
+    /// Copies `region`, of the same format, to (`x`, `y`).
+    pub fn copy_from(&mut self, region: &ColorAttachment, x: u32, y: u32) {
+        let copied = match (self, region) {
+            (ColorAttachment::R32F(i), ColorAttachment::R32F(r)) => i.copy_from(r, x, y),
+            (ColorAttachment::Rgb8(i), ColorAttachment::Rgb8(r)) => i.copy_from(r, x, y),
+            (ColorAttachment::Rgba8(i), ColorAttachment::Rgba8(r)) => i.copy_from(r, x, y),
+            (ColorAttachment::Srgb8(i), ColorAttachment::Srgb8(r)) => i.copy_from(r, x, y),
+            (ColorAttachment::Srgba8(i), ColorAttachment::Srgba8(r)) => i.copy_from(r, x, y),
+            (ColorAttachment::Rgb32F(i), ColorAttachment::Rgb32F(r)) => i.copy_from(r, x, y),
+            (ColorAttachment::Rgba32F(i), ColorAttachment::Rgba32F(r)) => i.copy_from(r, x, y),
+            _ => panic!("color attachment formats differ"),
+        };
+        copied.unwrap();
+    }
+}
+
+/// Values written by a fragment shader, one per color attachment of the
+/// framebuffer in the order they were added. Outputs left unset keep the
+/// color already in their attachment, and outputs without an attachment are
+/// dropped.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct FragmentOutputs([Option<Color>; MAX_COLOR_ATTACHMENTS]);
+
+impl FragmentOutputs {
+    /// Writes `color` to output `location`.
+    pub fn set(&mut self, location: usize, color: Color) {
+        self.0[location] = Some(color);
+    }
+
+    pub fn get(&self, location: usize) -> Option<Color> {
+        self.0[location]
+    }
+
+    pub fn clear(&mut self) {
+        self.0 = [None; MAX_COLOR_ATTACHMENTS];
+    }
+}
+
+/// A render target: named color attachments, all of the same size, and
+/// optional depth and stencil buffers.
+///
+/// Fragment shader output `i` goes to the `i`-th color attachment, so a
+/// shader can fill several at once, e.g. the color, normal and position
+/// images of a G-buffer.
+#[derive(Clone, Debug)]
+pub struct Framebuffer {
+    width: u32,
+    height: u32,
+    colors: Vec<(String, ColorAttachment)>,
+    /// Fragments are depth-tested when present
+    pub depth: Option<DepthBuffer>,
+    /// Fragments are stencil-tested when present
+    pub stencil: Option<StencilBuffer>,
+}
+
+impl Framebuffer {
+    /// Framebuffer without attachments.
+    pub fn new(width: u32, height: u32) -> Self {
+        Framebuffer { width, height, colors: Vec::new(), depth: None, stencil: None }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// Adds a black color attachment, returning the fragment output location
+    /// that writes to it.
+    pub fn add_color(&mut self, name: &str, format: ColorFormat) -> usize {
+        assert!(self.colors.len() < MAX_COLOR_ATTACHMENTS, "too many color attachments");
+        self.colors.push((name.to_string(), ColorAttachment::new(format, self.width, self.height)));
+        self.colors.len() - 1
+    }
+
+    /// Names of the color attachments, by location.
+    pub fn color_names(&self) -> impl Iterator<Item = &str> {
+        self.colors.iter().map(|(name, _)| name.as_str())
+    }
+
+    pub fn color(&self, name: &str) -> Option<&ColorAttachment> {
+        self.colors.iter().find(|(n, _)| n == name).map(|(_, c)| c)
+    }
+
+    pub fn color_mut(&mut self, name: &str) -> Option<&mut ColorAttachment> {
+        self.colors.iter_mut().find(|(n, _)| n == name).map(|(_, c)| c)
+    }
+
+    /// Color attachment at output `location`.
+    pub fn color_at(&self, location: usize) -> Option<&ColorAttachment> {
+        self.colors.get(location).map(|(_, c)| c)
+    }
+
+    /// Stores the outputs of a fragment in the pixel at (`x`, `y`).
+    pub fn write(&mut self, x: u32, y: u32, outputs: &FragmentOutputs) {
+        for (location, (_, color)) in self.colors.iter_mut().enumerate() {
+            if let Some(value) = outputs.get(location) {
+                color.put(x, y, value);
+            }
+        }
+    }
+
+    /// Copy of the `width` x `height` region at (`x`, `y`) of every attachment.
+    pub fn region(&self, x: u32, y: u32, width: u32, height: u32) -> Self {
+        Framebuffer {
+            width,
+            height,
+            colors: self.colors.iter().map(|(name, c)| (name.clone(), c.region(x, y, width, height))).collect(),
+            depth: self.depth.as_ref().map(|d| d.region(x, y, width, height)),
+            stencil: self.stencil.as_ref().map(|s| s.region(x, y, width, height)),
+        }
+    }
+
+    /// Copies every attachment of `region` to (`x`, `y`), see [`Framebuffer::region`].
+    pub fn copy_from(&mut self, region: &Framebuffer, x: u32, y: u32) {
+        for ((_, color), (_, from)) in self.colors.iter_mut().zip(&region.colors) {
+            color.copy_from(from, x, y);
+        }
+        if let (Some(depth), Some(from)) = (&mut self.depth, &region.depth) {
+            depth.copy_from(from, x, y);
+        }
+        if let (Some(stencil), Some(from)) = (&mut self.stencil, &region.stencil) {
+            stencil.copy_from(from, x, y);
+        }
+    }
+}
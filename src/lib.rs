@@ -0,0 +1,45 @@
+//! A small software rasterizer.
+//!
+//! The crate loads Wavefront OBJ models ([`model`]) with their materials
+//! ([`material`]), places them in scenes ([`scene`]), pushes their faces
+//! through a programmable shader ([`shaders`]) lit by light sources
+//! ([`light`]), and rasterizes them ([`my_gl`]) into a framebuffer
+//! ([`framebuffer`]) of color images, keeping the nearest fragments with a
+//! depth buffer ([`depth`]).
+//!
+//! ```no_run
+//! use nalgebra::{Matrix4, Vector3};
+//! use rasterizer::camera::Camera;
+//! use rasterizer::depth::DepthBuffer;
+//! use rasterizer::framebuffer::{ColorFormat, Framebuffer};
+//! use rasterizer::light::Light;
+//! use rasterizer::{model::Model, my_gl, shaders};
+//!
+//! let model = Model::from_file("head.obj").unwrap();
+//! let camera = Camera::new(Vector3::new(1., 1., 3.), Vector3::zeros(), Vector3::y());
+//! let viewport = my_gl::viewport(0., 0., 800., 800.);
+//!
+//! let mut framebuffer = Framebuffer::new(800, 800);
+//! framebuffer.add_color("color", ColorFormat::Srgb8);
+//! framebuffer.depth = Some(DepthBuffer::new(800, 800));
+//! let lights = vec![Light::directional(Vector3::z())];
+//! let uniforms = shaders::ShaderUniforms::new(camera.view_projection(), camera.view(), Matrix4::identity(), lights).unwrap();
+//! let pipeline = camera.pipeline(viewport);
+//! my_gl::draw(&model, &shaders::Shader, &uniforms, &pipeline, &mut framebuffer);
+//! framebuffer.color("color").unwrap().to_rgb8().save("head.png").unwrap();
+//! ```
+
+pub mod bounds;
+pub mod camera;
+pub mod color;
+pub mod depth;
+pub mod framebuffer;
+pub mod light;
+pub mod material;
+pub mod model;
+pub mod my_gl;
+pub mod scene;
+pub mod shaders;
+pub mod shadow;
+pub mod ssao;
+pub mod texture;
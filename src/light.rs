@@ -0,0 +1,131 @@
+use std::sync::Arc;
+use nalgebra::SVector;
+use crate::color::Color;
+use crate::shadow::ShadowMap;
+
+/// Fraction of the light left at distance `d`: `1 / (constant + linear d +
+/// quadratic d²)`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Attenuation {
+    pub constant: f32,
+    pub linear: f32,
+    pub quadratic: f32,
+}
+
+impl Attenuation {
+    /// Light that does not fade with distance.
+    pub const NONE: Attenuation = Attenuation { constant: 1., linear: 0., quadratic: 0. };
+
+    /// Physical falloff with the square of the distance, kept finite near the
+    /// light.
+    pub const INVERSE_SQUARE: Attenuation = Attenuation { constant: 1., linear: 0., quadratic: 1. };
+
+    pub fn factor(&self, distance: f32) -> f32 {
+        1. / (self.constant + self.linear * distance + self.quadratic * distance * distance).max(f32::EPSILON)
+    }
+}
+
+/// Where a light is and which way it shines, in world space.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum LightKind {
+    /// Light from infinitely far away, like the sun
+    Directional {
+        /// Direction towards the light
+        direction: SVector<f32, 3>,
+    },
+    /// Light shining equally in every direction from a point, like a bulb
+    Point {
+        position: SVector<f32, 3>,
+        attenuation: Attenuation,
+    },
+    /// Point light restricted to a cone, like a torch
+    Spot {
+        position: SVector<f32, 3>,
+        /// Axis of the cone, from the light outwards
+        direction: SVector<f32, 3>,
+        /// Half-angle in radians inside which the light is full
+        inner_angle: f32,
+        /// Half-angle in radians outside which there is no light
+        outer_angle: f32,
+        attenuation: Attenuation,
+    },
+}
+
+/// A light source lighting the shaders of this crate.
+#[derive(Clone, Debug)]
+pub struct Light {
+    pub kind: LightKind,
+    /// Color of the light, in linear light
+    pub color: Color,
+    /// Scale of `color`
+    pub intensity: f32,
+    /// Depth seen from the light, to shadow what it does not reach
+    pub shadow: Option<Arc<ShadowMap>>,
+}
+
+impl Light {
+    /// White light from the direction `direction`.
+    pub fn directional(direction: SVector<f32, 3>) -> Self {
+        Light::white(LightKind::Directional { direction: direction.normalize() })
+    }
+
+    /// White light at `position`, fading with the square of the distance.
+    pub fn point(position: SVector<f32, 3>) -> Self {
+        Light::white(LightKind::Point { position, attenuation: Attenuation::INVERSE_SQUARE })
+    }
+
+    /// White light at `position` shining along `direction`, full within
+    /// `inner_angle` of it and fading out to `outer_angle`.
+    pub fn spot(position: SVector<f32, 3>, direction: SVector<f32, 3>, inner_angle: f32, outer_angle: f32) -> Self {
+        Light::white(LightKind::Spot {
+            position,
+            direction: direction.normalize(),
+            inner_angle,
+            outer_angle,
+            attenuation: Attenuation::INVERSE_SQUARE,
+        })
+    }
+
+    fn white(kind: LightKind) -> Self {
+        Light { kind, color: Color::WHITE, intensity: 1., shadow: None }
+    }
+
+    /// Direction from the world-space point `p` towards the light, with the
+    /// light reaching `p` if it faced the light, shadows aside. `None` when
+    /// no light reaches `p`.
+    pub fn incident(&self, p: SVector<f32, 3>) -> Option<(SVector<f32, 3>, Color)> {
+        let radiance = self.color * self.intensity;
+        match self.kind {
+            LightKind::Directional { direction } => Some((direction, radiance)),
+            LightKind::Point { position, attenuation } => {
+                let (l, distance) = Light::towards(position, p)?;
+                Some((l, radiance * attenuation.factor(distance)))
+            }
+            LightKind::Spot { position, direction, inner_angle, outer_angle, attenuation } => {
+                let (l, distance) = Light::towards(position, p)?;
+                let (cos_inner, cos_outer) = (inner_angle.cos(), outer_angle.cos());
+                let cos = (-l).dot(&direction);
+                if cos <= cos_outer {
+                    return None;
+                }
+                // Smooth fade between the cones
+                let t = ((cos - cos_outer) / (cos_inner - cos_outer).max(f32::EPSILON)).min(1.);
+                let cone = t * t * (3. - 2. * t);
+                Some((l, radiance * (cone * attenuation.factor(distance))))
+            }
+        }
+    }
+
+    /// Unit direction from `p` to `position`, and the distance between them.
+    fn towards(position: SVector<f32, 3>, p: SVector<f32, 3>) -> Option<(SVector<f32, 3>, f32)> {
+        let d = position - p;
+        let distance = d.norm();
+        (distance > f32::EPSILON).then(|| (d / distance, distance))
+    }
+
+    /// Fraction of the light reaching the world-space point `p` past the
+    /// shadow map, `1` without one.
+    pub fn visibility(&self, p: SVector<f32, 3>) -> f32 {
+        self.shadow.as_ref().map_or(1., |map| map.visibility(p))
+    }
+}
@@ -1,69 +1,339 @@
-use image::{imageops, RgbImage, Rgb};
-mod my_gl;
-mod model;
-mod rgb;
-mod shaders;
-use my_gl::triangle;
+use std::sync::Arc;
+use image::{imageops, Rgb};
+use clap::Parser;
+use rasterizer::camera::{Camera, Projection};
+use rasterizer::color::Color;
+use rasterizer::depth::DepthBuffer;
+use rasterizer::framebuffer::{ColorFormat, Framebuffer};
+use rasterizer::light::Light;
+use rasterizer::scene::Scene;
+use rasterizer::shadow::ShadowMap;
+use rasterizer::ssao::{self, Ssao};
+use rasterizer::material::TextureCache;
+use rasterizer::my_gl::{Pipeline, Winding};
+use rasterizer::{model, my_gl, shaders};
 use nalgebra::{SVector, SMatrix, Vector3};
 
-const WIDTH: f32 = 800.0;
-const HEIGHT: f32 = 800.;
+const BASE_COLOR: Rgb<u8> = Rgb([255, 155, 0]);
 
-const LIGHT_DIR: SVector<f32, 3> = Vector3::new(0., 0., 1.);
-const EYE: SVector<f32, 3> = Vector3::new(1., 1., 3.);
-const CENTER: SVector<f32, 3> = Vector3::new(0., 0., 0.);
-const UP: SVector<f32, 3> = Vector3::new(0., 1., 0.);
-const BASE_COLOR: Rgb<u8> = Rgb([255 as u8, 155 as u8, 0 as u8]);
+/// Side in texels of the shadow map
+const SHADOW_SIZE: u32 = 1024;
 
+/// How the scene is projected to the image
+#[derive(clap::ValueEnum, Copy, Clone, Debug, PartialEq, Eq)]
+enum ProjectionKind {
+    Perspective,
+    Orthographic,
+    /// Orthographic, looking down the diagonal of the axes
+    Isometric,
+    /// Orthographic, with the 2:1 slopes of pixel art
+    Dimetric,
+    /// Oblique, with depth at full length
+    Cavalier,
+    /// Oblique, with depth at half length
+    Cabinet,
+}
+
+/// Faces left out of the image by their winding on the screen
+#[derive(clap::ValueEnum, Copy, Clone, Debug, PartialEq, Eq)]
+enum CullKind {
+    None,
+    /// Clockwise faces, the back faces of OBJ models
+    Cw,
+    /// Counterclockwise faces
+    Ccw,
+}
+
+/// Render an OBJ model to an image
+#[derive(Parser, Debug)]
+#[command(version, about)]
+struct Args {
+    /// Wavefront OBJ file to render
+    #[arg(short, long, default_value = "./obj/diablo/diablo.obj")]
+    model: String,
+
+    /// Scene file placing several models, instead of --model and its maps
+    #[arg(long, conflicts_with_all = ["model", "diffuse", "normal", "specular"])]
+    scene: Option<String>,
+
+    /// Diffuse texture, replacing the `map_Kd` of every material
+    #[arg(long)]
+    diffuse: Option<String>,
+
+    /// Tangent-space normal map, replacing the `map_Bump` of every material
+    #[arg(long)]
+    normal: Option<String>,
+
+    /// Specular map, replacing the `map_Ks` of every material
+    #[arg(long)]
+    specular: Option<String>,
+
+    /// Output image width in pixels
+    #[arg(long, default_value_t = 800, value_parser = clap::value_parser!(u32).range(1..))]
+    width: u32,
+
+    /// Output image height in pixels
+    #[arg(long, default_value_t = 800, value_parser = clap::value_parser!(u32).range(1..))]
+    height: u32,
+
+    /// Camera position, as x,y,z
+    #[arg(long, default_value = "1,1,3", value_parser = parse_vec3, allow_hyphen_values = true)]
+    eye: SVector<f32, 3>,
+
+    /// Point the camera looks at, as x,y,z
+    #[arg(long, default_value = "0,0,0", value_parser = parse_vec3, allow_hyphen_values = true)]
+    center: SVector<f32, 3>,
+
+    /// Camera up direction, as x,y,z
+    #[arg(long, default_value = "0,1,0", value_parser = parse_vec3, allow_hyphen_values = true)]
+    up: SVector<f32, 3>,
+
+    /// Projection of the image. The parallel ones see as much of the plane
+    /// through --center as the perspective does; isometric and dimetric keep
+    /// the distance from --eye to --center but not its direction
+    #[arg(long, value_enum, default_value_t = ProjectionKind::Perspective)]
+    projection: ProjectionKind,
+
+    /// Frame the whole model: look at its center from the direction of
+    /// --eye, from just far enough, with clip planes around it instead of
+    /// --near and --far
+    #[arg(long)]
+    fit: bool,
+
+    /// Vertical field of view in degrees
+    #[arg(long, default_value_t = 45.)]
+    fov: f32,
+
+    /// Distance from the camera to the near clip plane
+    #[arg(long, default_value_t = 0.1)]
+    near: f32,
+
+    /// Distance from the camera to the far clip plane
+    #[arg(long, default_value_t = 100.)]
+    far: f32,
+
+    /// Direction towards the light, as x,y,z
+    #[arg(long, default_value = "0,0,1", value_parser = parse_vec3, allow_hyphen_values = true)]
+    light: SVector<f32, 3>,
+
+    /// Position of an additional point light, as x,y,z; may be repeated
+    #[arg(long, value_parser = parse_vec3, allow_hyphen_values = true)]
+    point_light: Vec<SVector<f32, 3>>,
+
+    /// Shader used to color the model: phong (textured, normal-mapped),
+    /// gouraud or cartoon
+    #[arg(long, default_value = "phong")]
+    shader: String,
+
+    /// Drop the faces with this winding on the screen
+    #[arg(long, value_enum, default_value_t = CullKind::Cw)]
+    cull: CullKind,
+
+    /// Print how many faces were culled, clipped and drawn
+    #[arg(long)]
+    stats: bool,
+
+    /// Do not cast shadows
+    #[arg(long)]
+    no_shadows: bool,
+
+    /// Darken creases with screen-space ambient occlusion
+    #[arg(long)]
+    ssao: bool,
+
+    /// Distance in pixels within which geometry occludes, for --ssao
+    #[arg(long, default_value_t = 16.)]
+    ssao_radius: f32,
+
+    /// Directions sampled around each pixel, for --ssao
+    #[arg(long, default_value_t = 8, value_parser = clap::value_parser!(u32).range(1..))]
+    ssao_samples: u32,
+
+    /// Also save the ambient occlusion map to this path
+    #[arg(long)]
+    ssao_output: Option<String>,
+
+    /// Number of rendering threads, all cores by default
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    threads: Option<u32>,
+
+    /// Output image path
+    #[arg(short, long, default_value = "test.png")]
+    output: String,
+
+    /// Also save the view-space shading normals, mapped to colors, to this path
+    #[arg(long)]
+    normals: Option<String>,
+}
+
+impl Args {
+    fn validate(&self, shaders: &shaders::ShaderRegistry) -> Result<(), String> {
+        let view = self.eye - self.center;
+        if view.norm() < f32::EPSILON {
+            return Err("--eye and --center must differ".to_string());
+        }
+        if self.up.cross(&view).norm() < f32::EPSILON {
+            return Err("--up must not be parallel to the viewing direction".to_string());
+        }
+        if !(self.fov > 0. && self.fov < 180.) {
+            return Err("--fov must be between 0 and 180 degrees".to_string());
+        }
+        if !(self.near > 0. && self.far > self.near && self.far.is_finite()) {
+            return Err("--near and --far must satisfy 0 < near < far".to_string());
+        }
+        if self.light.norm() < f32::EPSILON {
+            return Err("--light must be a non-zero vector".to_string());
+        }
+        if !shaders.names().any(|name| name == self.shader) {
+            let names: Vec<&str> = shaders.names().collect();
+            return Err(format!("unknown shader `{}`, expected one of: {}", self.shader, names.join(", ")));
+        }
+        Ok(())
+    }
+
+    /// Camera of the image, whose width over height is `aspect`.
+    fn camera(&self, aspect: f32) -> Camera {
+        let fovy = self.fov.to_radians();
+        let distance = (self.eye - self.center).norm();
+        let height = 2. * distance * (fovy / 2.).tan();
+        let camera = Camera::new(self.eye, self.center, self.up);
+        let camera = match self.projection {
+            ProjectionKind::Perspective => Camera { projection: Projection::Perspective { fovy }, ..camera },
+            ProjectionKind::Orthographic => Camera { projection: Projection::Orthographic { height }, ..camera },
+            ProjectionKind::Isometric => Camera::isometric(self.center, distance, height),
+            ProjectionKind::Dimetric => Camera::dimetric(self.center, distance, height),
+            ProjectionKind::Cavalier => Camera { projection: Projection::cavalier(height), ..camera },
+            ProjectionKind::Cabinet => Camera { projection: Projection::cabinet(height), ..camera },
+        };
+        Camera { aspect, near: self.near, far: self.far, ..camera }
+    }
+}
+
+fn parse_vec3(s: &str) -> Result<SVector<f32, 3>, String> {
+    let values = s
+        .split(',')
+        .map(|v| v.trim().parse::<f32>().map_err(|e| format!("invalid number `{}`: {}", v.trim(), e)))
+        .collect::<Result<Vec<f32>, String>>()?;
+
+    match values[..] {
+        [x, y, z] if values.iter().all(|v| v.is_finite()) => Ok(Vector3::new(x, y, z)),
+        [_, _, _] => Err("components must be finite".to_string()),
+        _ => Err(format!("expected three comma-separated values, got {}", values.len())),
+    }
+}
+
+fn load_scene(args: &Args) -> Result<Scene, model::ModelError> {
+    if let Some(path) = &args.scene {
+        return Scene::from_file(path);
+    }
+    let mut model = model::Model::from_file(&args.model)?;
+    let mut textures = TextureCache::default();
+    model.override_textures(args.diffuse.as_deref(), args.normal.as_deref(), args.specular.as_deref(), &mut textures)?;
+    Ok(Scene::single(model))
+}
 
 fn main() {
-    let mut zbuffer: Vec<f32> = vec![-std::f32::MAX; (WIDTH * HEIGHT) as usize];
-
-    let mut imgbuf: RgbImage = image::ImageBuffer::new(WIDTH as u32, HEIGHT as u32);
-
-    // let model = match model::Model::from_file(
-    //     "./obj/african_head.obj",
-    //     "./obj/african_head_diffuse.tga",
-    //     "./obj/african_head_nm_tangent.tga",
-    //     "./obj/african_head_spec.tga",
-    // ) {
-    //     Ok(m) => m,
-    //     Err(e) => {
-    //         println!("Error {}", e.to_string());
-    //         std::process::exit(1)
-    //     }
-    // };
-
-    let model = match model::Model::from_file(
-        "./obj/diablo/diablo.obj",
-        "./obj/diablo/diablo3_pose_diffuse.tga",
-        "./obj/diablo/diablo3_pose_nm_tangent.tga",
-        "./obj/diablo/diablo3_pose_spec.tga",
-    ) {
-        Ok(m) => m,
+    let args = Args::parse();
+    let registry = shaders::ShaderRegistry::default();
+    if let Err(e) = args.validate(&registry) {
+        eprintln!("error: {}", e);
+        std::process::exit(2)
+    }
+
+    if let Some(threads) = args.threads {
+        rayon::ThreadPoolBuilder::new().num_threads(threads as usize).build_global().unwrap();
+    }
+
+    let (width, height) = (args.width as f32, args.height as f32);
+
+    let mut framebuffer = Framebuffer::new(args.width, args.height);
+    framebuffer.add_color("color", ColorFormat::Srgb8);
+    framebuffer.add_color("normal", ColorFormat::Rgba8);
+    framebuffer.depth = Some(DepthBuffer::new(args.width, args.height));
+
+    let scene = match load_scene(&args) {
+        Ok(s) => s,
         Err(e) => {
-            println!("Error {}", e.to_string());
+            eprintln!("Error {}", e);
             std::process::exit(1)
         }
     };
+    // Instances share their meshes, whose warnings are printed once
+    let mut meshes: Vec<&Arc<model::Model>> = Vec::new();
+    for mesh in scene.nodes.iter().filter_map(|node| node.mesh.as_ref()) {
+        if !meshes.iter().any(|m| Arc::ptr_eq(m, mesh)) {
+            meshes.push(mesh);
+        }
+    }
+    for warning in meshes.iter().flat_map(|m| &m.warnings) {
+        eprintln!("Warning {}", warning);
+    }
 
-    let modelview: SMatrix<f32, 4, 4> = my_gl::lookat(EYE, CENTER, UP);
-    let projection: SMatrix<f32, 4, 4> = my_gl::projection(-1. / (EYE - CENTER).z);
-    let viewport: SMatrix<f32, 4, 4> = my_gl::viewport(WIDTH / 8., HEIGHT / 8., WIDTH * 3./4., HEIGHT* 3./4.);
+    let camera = args.camera(width / height);
+    let camera = match scene.bounding_sphere() {
+        Some(sphere) if args.fit => camera.fit(&sphere),
+        _ => camera,
+    };
+    let viewport: SMatrix<f32, 4, 4> = my_gl::viewport(0., 0., width, height);
 
-    let my_shader = shaders::Shader::new(projection * modelview);
-    let mut shader = shaders::AnyShader::from(my_shader);
+    let mut lights = vec![Light {
+        shadow: (!args.no_shadows).then(|| Arc::new(ShadowMap::render_scene(&scene, args.light, SHADOW_SIZE))),
+        ..Light::directional(args.light)
+    }];
+    lights.extend(args.point_light.iter().map(|&position| Light::point(position)));
 
-    let transformation: SMatrix<f32, 4, 4> = viewport * projection * modelview;
+    let params = shaders::ShaderParams {
+        transformation: camera.view_projection(),
+        modelview: camera.view(),
+        model: SMatrix::identity(),
+        lights,
+        base_color: Color::from_srgb8(BASE_COLOR),
+    };
 
-    for i in 0..model.nfaces as usize {
-        let mut screen_coords: Vec<SVector<f32, 4>> = Vec::new(); // Is it bad to use let inside a for loop? @TODO: Investigate
-        for j in 0..3 as usize {
-            screen_coords.push(shader.vertex(&model, transformation, i, j));
+    let cull = match args.cull {
+        CullKind::None => None,
+        CullKind::Cw => Some(Winding::Clockwise),
+        CullKind::Ccw => Some(Winding::CounterClockwise),
+    };
+    let pipeline = Pipeline { cull, ..camera.pipeline(viewport) };
+
+    let create = |params: &shaders::ShaderParams| registry.create(&args.shader, params);
+    let stats = scene.draw(&params, create, &pipeline, &mut framebuffer);
+    if args.stats {
+        println!(
+            "{} faces: {} frustum culled, {} back-face culled, {} clipped, {} drawn",
+            stats.faces, stats.frustum_culled, stats.back_face_culled, stats.clipped, stats.drawn(),
+        );
+    }
+
+    if args.ssao || args.ssao_output.is_some() {
+        let ssao = Ssao {
+            radius: args.ssao_radius,
+            samples: args.ssao_samples,
+            depth_scale: camera.depth_scale((camera.eye - camera.center).norm(), height),
+            strength: 1.,
+        };
+        let ao = ssao.compute(framebuffer.depth.as_ref().unwrap(), framebuffer.color("normal"));
+        if let Some(path) = &args.ssao_output {
+            if let Err(e) = imageops::flip_vertical(&ssao::to_gray8(&ao)).save(path) {
+                eprintln!("Error saving {}: {}", path, e);
+                std::process::exit(1)
+            }
+        }
+        if args.ssao {
+            ssao::composite(framebuffer.color_mut("color").unwrap(), &ao);
         }
-        triangle(screen_coords, &model, &shader, &mut zbuffer, &mut imgbuf, BASE_COLOR);  // I should use shader.vaying_tri instead of screen_coords
     }
 
-    imgbuf = imageops::flip_vertical(&imgbuf);
-    imgbuf.save("test.png").unwrap();
+    let mut outputs = vec![("color", &args.output)];
+    if let Some(path) = &args.normals {
+        outputs.push(("normal", path));
+    }
+    for (name, path) in outputs {
+        let image = imageops::flip_vertical(&framebuffer.color(name).unwrap().to_rgb8());
+        if let Err(e) = image.save(path) {
+            eprintln!("Error saving {}: {}", path, e);
+            std::process::exit(1)
+        }
+    }
 }
@@ -0,0 +1,213 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, prelude::*};
+use std::path::Path;
+use std::sync::Arc;
+use nalgebra::{SVector, Vector3};
+use crate::color::{Color, ColorSpace};
+use crate::model::{Location, ModelError, parse_float_vector, tokenize};
+use crate::texture::{Sampler, TexCoords, Texture};
+
+
+/// Surface description from a Wavefront MTL material library.
+#[derive(Clone)]
+pub struct Material {
+    pub name: String,
+    /// Ambient color, `Ka`, scaling the ambient light
+    pub ambient: SVector<f32, 3>,
+    /// Diffuse color, `Kd`
+    pub diffuse: SVector<f32, 3>,
+    /// Specular color, `Ks`
+    pub specular: SVector<f32, 3>,
+    /// Specular exponent, `Ns`
+    pub shininess: f32,
+    /// Opacity, `d` (or `1 - Tr`). It is only written as the alpha of the
+    /// color by [`crate::shaders::Shader`], which discards faces of zero
+    /// opacity, and is otherwise ignored: nothing is blended.
+    pub dissolve: f32,
+    /// `map_Kd`
+    pub diffuse_map: Option<Arc<Texture>>,
+    /// Tangent-space normal map, `map_Bump`, `bump` or `norm`
+    pub normal_map: Option<Arc<Texture>>,
+    /// `map_Ks` or `map_Ns`; its red channel is used as the specular exponent
+    pub specular_map: Option<Arc<Texture>>,
+    /// How the maps are sampled
+    pub sampler: Sampler,
+}
+
+impl Default for Material {
+    fn default() -> Self {
+        Material {
+            name: "default".to_string(),
+            ambient: Vector3::new(1., 1., 1.),
+            diffuse: Vector3::new(1., 1., 1.),
+            specular: Vector3::new(0.3, 0.3, 0.3),
+            shininess: 10.,
+            dissolve: 1.,
+            diffuse_map: None,
+            normal_map: None,
+            specular_map: None,
+            sampler: Sampler::default(),
+        }
+    }
+}
+
+impl Material {
+    /// Normal map sample at `coords`, in tangent space, or `None` without a
+    /// normal map.
+    pub fn sample_normal(&self, coords: &TexCoords) -> Option<SVector<f32, 3>> {
+        let c = self.sampler.sample(self.normal_map.as_deref()?, coords);
+        // `image` gives RGB whatever the file stores, e.g. BGR in TGA files
+        Some(c * 2. - Vector3::repeat(1.))
+    }
+
+    /// Diffuse color at `coords`, in linear light: `Kd` tinted by `map_Kd`.
+    pub fn sample_diffuse(&self, coords: &TexCoords) -> Color {
+        match &self.diffuse_map {
+            Some(map) => Color::from(self.sampler.sample(map, coords).component_mul(&self.diffuse)),
+            None => Color::from(self.diffuse),
+        }
+    }
+
+    /// Specular exponent at `coords`.
+    pub fn sample_specular(&self, coords: &TexCoords) -> f32 {
+        match &self.specular_map {
+            Some(map) => self.sampler.sample(map, coords).x * 255.,
+            None => self.shininess,
+        }
+    }
+}
+
+/// Texture maps shared between the materials of a model, keyed by path and
+/// color space.
+#[derive(Default)]
+pub struct TextureCache {
+    textures: HashMap<(String, ColorSpace), Arc<Texture>>,
+}
+
+impl TextureCache {
+    /// Loads the texture at `path`, whose channels are encoded in
+    /// `color_space`, or returns the copy loaded before.
+    pub fn load(&mut self, path: &str, color_space: ColorSpace) -> Result<Arc<Texture>, ModelError> {
+        let key = (path.to_string(), color_space);
+        if let Some(texture) = self.textures.get(&key) {
+            return Ok(texture.clone());
+        }
+        let texture = match image::open(path) {
+            Ok(img) => Arc::new(Texture::new(img.to_rgb8(), color_space)),
+            Err(source) => return Err(ModelError::Texture { path: path.to_string(), source }),
+        };
+        self.textures.insert(key, texture.clone());
+        Ok(texture)
+    }
+}
+
+/// Reads every material of the MTL file at `path`. Texture paths are resolved
+/// relative to the directory of the library.
+pub fn load_mtl(path: &str, textures: &mut TextureCache) -> Result<Vec<Material>, ModelError> {
+    let file = File::open(path).map_err(|source| ModelError::Io { path: path.to_string(), source })?;
+    let dir = Path::new(path).parent().unwrap_or(Path::new(""));
+
+    let mut materials: Vec<Material> = Vec::new();
+    for (n, line) in BufReader::new(file).lines().enumerate() {
+        let l = line.map_err(|source| ModelError::Io { path: path.to_string(), source })?;
+        let at = Location { path: path.to_string(), line: n + 1, column: 1 };
+        let words = tokenize(&l);
+        let Some(&(_, keyword)) = words.first() else {
+            continue;
+        };
+
+        if keyword == "newmtl" {
+            let Some(&(_, name)) = words.get(1) else {
+                return Err(ModelError::MissingValue { at, keyword: keyword.to_string(), expected: 1 });
+            };
+            materials.push(Material { name: name.to_string(), ..Material::default() });
+            continue;
+        }
+        let Some(material) = materials.last_mut() else {
+            continue;
+        };
+        match keyword {
+            "Ka" => material.ambient = parse_float_vector(&at, &words, 3)?,
+            "Kd" => material.diffuse = parse_float_vector(&at, &words, 3)?,
+            "Ks" => material.specular = parse_float_vector(&at, &words, 3)?,
+            "Ns" => material.shininess = parse_float_vector(&at, &words, 1)?.x,
+            "d" => material.dissolve = parse_float_vector(&at, &words, 1)?.x,
+            "Tr" => material.dissolve = 1. - parse_float_vector(&at, &words, 1)?.x,
+            "map_Kd" | "map_Bump" | "map_bump" | "bump" | "norm" | "map_Ks" | "map_Ns" => {
+                // Options such as `-bm 0.5` come first, the file name is last
+                let Some(&(_, file)) = words.get(1..).and_then(|w| w.last()) else {
+                    return Err(ModelError::MissingValue { at, keyword: keyword.to_string(), expected: 1 });
+                };
+                // Colors are gamma-encoded, normals and exponents are not
+                let color_space = if keyword == "map_Kd" { ColorSpace::Srgb } else { ColorSpace::Linear };
+                let texture = Some(textures.load(&dir.join(file).to_string_lossy(), color_space)?);
+                match keyword {
+                    "map_Kd" => material.diffuse_map = texture,
+                    "map_Ks" | "map_Ns" => material.specular_map = texture,
+                    _ => material.normal_map = texture,
+                }
+            }
+            _ => (),
+        }
+    }
+    Ok(materials)
+}
+
+#[cfg(test)]
+mod tests {
+    use image::RgbImage;
+    use super::*;
+    use crate::model::tests::write_files;
+
+    fn load(test: &str, contents: &str) -> Result<Vec<Material>, ModelError> {
+        let dir = write_files(test, &[("test.mtl", contents)]);
+        RgbImage::new(2, 2).save(dir.join("red.png")).unwrap();
+        load_mtl(&dir.join("test.mtl").to_string_lossy(), &mut TextureCache::default())
+    }
+
+    #[test]
+    fn parse_materials() {
+        let materials = load("parse_materials", "\
+            Kd 0 1 0          # before any material, ignored
+            newmtl red
+            Ka 0.1 0.2 0.3
+            Kd 1 0 0
+            Ks 0.5 0.5 0.5
+            Ns 50
+            d 0.5
+            map_Kd -bm 0.5 red.png
+            newmtl glass
+            Tr 0.75
+            illum 4           # unsupported, ignored
+        ").unwrap();
+        assert_eq!(materials.len(), 2);
+        let (red, glass) = (&materials[0], &materials[1]);
+        assert_eq!(red.name, "red");
+        assert_eq!(red.ambient, Vector3::new(0.1, 0.2, 0.3));
+        assert_eq!(red.diffuse, Vector3::new(1., 0., 0.));
+        assert_eq!(red.specular, Vector3::new(0.5, 0.5, 0.5));
+        assert_eq!((red.shininess, red.dissolve), (50., 0.5));
+        assert_eq!(red.diffuse_map.as_ref().map(|map| map.dimensions()), Some((2, 2)));
+        assert!(red.normal_map.is_none() && red.specular_map.is_none());
+        // Everything else is left to the defaults
+        assert_eq!(glass.name, "glass");
+        assert_eq!(glass.dissolve, 0.25);
+        assert_eq!(glass.diffuse, Material::default().diffuse);
+        assert!(glass.diffuse_map.is_none());
+    }
+
+    #[test]
+    fn material_errors_are_located() {
+        let Err(ModelError::MalformedNumber { at, token }) = load("mtl_malformed", "newmtl a\nKd 1 x 0\n") else {
+            panic!("x parsed");
+        };
+        assert_eq!((at.line, at.column, token.as_str()), (2, 6, "x"));
+        let Err(ModelError::MissingValue { at, keyword, .. }) = load("mtl_missing", "newmtl a\n\nKs 1 1\n") else {
+            panic!("Ks parsed");
+        };
+        assert_eq!((at.line, keyword.as_str()), (3, "Ks"));
+        assert!(matches!(load("mtl_unnamed", "newmtl\n"), Err(ModelError::MissingValue { .. })));
+        assert!(matches!(load("mtl_texture", "newmtl a\nmap_Kd missing.png\n"), Err(ModelError::Texture { .. })));
+    }
+}
@@ -1,162 +1,716 @@
+use std::collections::HashMap;
+use std::fmt;
 use std::fs::File;
-use std::io::{BufReader, prelude::*, Error};
-use nalgebra::{SVector, Vector3};
-use image::{Pixel, Rgb, ImageBuffer};
+use std::io::{BufReader, prelude::*};
+use std::path::Path;
+use nalgebra::{SVector, Vector3, Vector4};
+use crate::bounds::{Aabb, BoundingSphere};
+use crate::color::{Color, ColorSpace};
+use crate::material::{Material, TextureCache, load_mtl};
+use crate::texture::TexCoords;
 
 
-type Result<T> = std::result::Result<T, Error>;
+type Result<T> = std::result::Result<T, ModelError>;
 
+/// Position in a source file, used to point at the offending token.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Location {
+    pub path: String,
+    /// 1-based line number
+    pub line: usize,
+    /// 1-based column of the token
+    pub column: usize,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.path, self.line, self.column)
+    }
+}
+
+/// Everything that can go wrong while loading a [`Model`] or a
+/// [`crate::scene::Scene`].
+#[derive(Debug)]
+pub enum ModelError {
+    /// The OBJ or scene file could not be opened or read.
+    Io { path: String, source: std::io::Error },
+    /// A texture map could not be opened or decoded.
+    Texture { path: String, source: image::ImageError },
+    /// A token that should be a number is not one.
+    MalformedNumber { at: Location, token: String },
+    /// A statement has fewer values than it needs.
+    MissingValue { at: Location, keyword: String, expected: usize },
+    /// A face corner is not of the form `v`, `v/vt`, `v//vn` or `v/vt/vn`, or uses index 0.
+    BadFaceIndex { at: Location, token: String },
+    /// A face corner refers to an element the file does not define.
+    IndexOutOfRange { at: Location, kind: &'static str, index: i32, count: usize },
+    /// `usemtl` names a material no material library defines.
+    UnknownMaterial { at: Location, name: String },
+    /// The material library named at `at` could not be loaded.
+    MaterialLibrary { at: Location, source: Box<ModelError> },
+    /// A scene statement names a mesh or node defined nowhere before it.
+    UnknownReference { at: Location, kind: &'static str, name: String },
+    /// A scene statement defines a mesh under a name already taken.
+    Redefinition { at: Location, kind: &'static str, name: String },
+    /// A scene statement that applies to the last `after` statement comes
+    /// before any.
+    MisplacedStatement { at: Location, keyword: String, after: &'static str },
+    /// A statement has a number of values it does not accept.
+    WrongValueCount { at: Location, keyword: String, expected: &'static str, found: usize },
+    /// A scene transform has no inverse, e.g. a zero scale.
+    SingularTransform { at: Location },
+}
+
+impl fmt::Display for ModelError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ModelError::Io { path, source } => write!(f, "{}: {}", path, source),
+            ModelError::Texture { path, source } => write!(f, "{}: cannot load texture: {}", path, source),
+            ModelError::MalformedNumber { at, token } => write!(f, "{}: malformed number `{}`", at, token),
+            ModelError::MissingValue { at, keyword, expected } => {
+                write!(f, "{}: `{}` expects at least {} values", at, keyword, expected)
+            }
+            ModelError::BadFaceIndex { at, token } => write!(f, "{}: bad face index `{}`", at, token),
+            ModelError::IndexOutOfRange { at, kind, index, count } => {
+                write!(f, "{}: {} index {} out of range, the file defines {}", at, kind, index, count)
+            }
+            ModelError::UnknownMaterial { at, name } => write!(f, "{}: unknown material `{}`", at, name),
+            ModelError::MaterialLibrary { at, source } => write!(f, "{}: cannot load material library: {}", at, source),
+            ModelError::UnknownReference { at, kind, name } => write!(f, "{}: unknown {} `{}`", at, kind, name),
+            ModelError::Redefinition { at, kind, name } => write!(f, "{}: {} `{}` is already defined", at, kind, name),
+            ModelError::MisplacedStatement { at, keyword, after } => {
+                write!(f, "{}: `{}` must come after a `{}` statement", at, keyword, after)
+            }
+            ModelError::WrongValueCount { at, keyword, expected, found } => {
+                write!(f, "{}: `{}` expects {} values, got {}", at, keyword, expected, found)
+            }
+            ModelError::SingularTransform { at } => write!(f, "{}: singular transform, scales must be finite and non-zero", at),
+        }
+    }
+}
+
+impl std::error::Error for ModelError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ModelError::Io { source, .. } => Some(source),
+            ModelError::Texture { source, .. } => Some(source),
+            ModelError::MaterialLibrary { source, .. } => Some(source.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+/// Interleaved attributes of one model vertex.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Vertex {
+    pub position: SVector<f32, 3>,
+    pub uv: SVector<f32, 3>,
+    pub normal: SVector<f32, 3>,
+    /// Direction of increasing `u`, orthogonal to the normal; `w` is the sign
+    /// of the bitangent, `v` growing along `w * normal × tangent`
+    pub tangent: SVector<f32, 4>,
+}
+
+/// Triangle mesh loaded from a Wavefront OBJ file, with its materials.
+///
+/// Corners sharing position, texture coordinates and normal are stored once in
+/// `vertices`; every three entries of `indices` make a face.
 pub struct Model {
-    // TODO: use index and vertex buffers
     pub nfaces: i32,
     pub nverts: i32,
-    pub faces: Vec<Vec<i32>>,
-    pub faces_diffuse_coords: Vec<Vec<i32>>,
-    pub faces_normal_coords: Vec<Vec<i32>>,
-    pub verts: Vec<SVector<f32, 3>>,
-    pub uv_: Vec<SVector<f32, 3>>,
-    pub norms: Vec<SVector<f32, 3>>,
-    pub diffuse_map: ImageBuffer<image::Rgb<u8>, Vec<u8>>,
-    pub normal_map: ImageBuffer<image::Rgb<u8>, Vec<u8>>,
-    pub specular_map: ImageBuffer<image::Rgb<u8>, Vec<u8>>
+    pub vertices: Vec<Vertex>,
+    pub indices: Vec<u32>,
+    pub materials: Vec<Material>,
+    /// Index into `materials` of every face
+    pub face_materials: Vec<usize>,
+    /// Problems that did not stop the model from loading, such as a missing
+    /// material library
+    pub warnings: Vec<ModelError>,
+}
+
+/// A face corner as written in the file: 0-based indices, with the optional
+/// texture coordinate and normal references.
+struct Corner {
+    vert: i32,
+    uv: Option<i32>,
+    normal: Option<i32>,
+    at: Location,
+}
+
+/// A face before triangulation.
+struct Polygon {
+    material: usize,
+    corners: Vec<Corner>,
+}
+
+/// The `v`, `vt` and `vn` statements of an OBJ file, indexed by face corners.
+#[derive(Default)]
+struct Attributes {
+    verts: Vec<SVector<f32, 3>>,
+    uvs: Vec<SVector<f32, 3>>,
+    norms: Vec<SVector<f32, 3>>,
 }
 
 impl Model {
-    pub fn from_file(obj_file: &str, diffuse_file: &str, normal_file: &str, specular_file: &str) -> Result<Self> {
-        let file = File::open(obj_file)?;//.expect("file not found!");
-        let diffuse_map = image::open(diffuse_file).unwrap().to_rgb8();
-        let normal_map = image::open(normal_file).unwrap().to_rgb8();
-        let specular_map = image::open(specular_file).unwrap().to_rgb8();
+    /// Loads `obj_file` and the material libraries it references with `mtllib`.
+    ///
+    /// Faces may use any of the `v`, `v/vt`, `v//vn` and `v/vt/vn` forms, with
+    /// negative indices counting back from the last element read. Polygons are
+    /// triangulated, and corners without texture coordinates or normals get a
+    /// default coordinate and a smooth vertex normal. Faces before any `usemtl`
+    /// get a white [`Material::default`], as do the faces of a material that
+    /// is not found: libraries that cannot be loaded and unknown materials
+    /// only add to [`Model::warnings`].
+    pub fn from_file(obj_file: &str) -> Result<Self> {
+        Model::from_file_with_textures(obj_file, &mut TextureCache::default())
+    }
+
+    /// Like [`Model::from_file`], sharing the texture maps of `textures`
+    /// with the models loaded before.
+    pub fn from_file_with_textures(obj_file: &str, textures: &mut TextureCache) -> Result<Self> {
+        let file = File::open(obj_file).map_err(|source| ModelError::Io { path: obj_file.to_string(), source })?;
+        let dir = Path::new(obj_file).parent().unwrap_or(Path::new(""));
 
         let mut model = Model {
             nfaces: 0,
             nverts: 0,
-            faces: Vec::new(),
-            faces_diffuse_coords: Vec::new(),
-            faces_normal_coords: Vec::new(),
-            verts: Vec::new(),
-            uv_: Vec::new(),
-            norms: Vec::new(),
-            diffuse_map: diffuse_map,
-            normal_map: normal_map,
-            specular_map: specular_map
+            vertices: Vec::new(),
+            indices: Vec::new(),
+            materials: Vec::new(),
+            face_materials: Vec::new(),
+            warnings: Vec::new(),
         };
 
         let buf_reader = BufReader::new(file);
-      
-        for line in buf_reader.lines() {
-            let l = line?;
-            if l.len() == 0 {
+        let mut attributes = Attributes::default();
+        let mut polygons: Vec<Polygon> = Vec::new();
+        let mut material_ids: HashMap<String, usize> = HashMap::new();
+        let mut material: Option<usize> = None;
+        let mut default_material: Option<usize> = None;
+
+        for (n, line) in buf_reader.lines().enumerate() {
+            let l = line.map_err(|source| ModelError::Io { path: obj_file.to_string(), source })?;
+            let at = Location { path: obj_file.to_string(), line: n + 1, column: 1 };
+            let words = tokenize(&l);
+            let Some(&(_, keyword)) = words.first() else {
                 continue;
-            }
-            match &l[..2] {
-                "v " => Model::add_line_float_vector(&mut model.verts, &l),
-                "f " => Model::add_face_from_line(&mut model, &l),
-                "vt" => Model::add_line_float_vector(&mut model.uv_, &l),
-                "vn" => Model::add_line_float_vector(&mut model.norms, &l),
+            };
+            match keyword {
+                "v" => attributes.verts.push(parse_float_vector(&at, &words, 3)?),
+                "f" => {
+                    let material = *material.get_or_insert_with(|| {
+                        *default_material.get_or_insert_with(|| model.add_material(Material::default()))
+                    });
+                    polygons.push(Polygon { material, corners: attributes.parse_face(&at, &words)? });
+                }
+                "vt" => attributes.uvs.push(parse_float_vector(&at, &words, 1)?),
+                "vn" => attributes.norms.push(parse_float_vector(&at, &words, 3)?),
+                "mtllib" => {
+                    for &(column, library) in &words[1..] {
+                        match load_mtl(&dir.join(library).to_string_lossy(), textures) {
+                            Ok(materials) => {
+                                for m in materials {
+                                    material_ids.insert(m.name.clone(), model.add_material(m));
+                                }
+                            }
+                            Err(source) => {
+                                let at = Location { column, ..at.clone() };
+                                model.warnings.push(ModelError::MaterialLibrary { at, source: Box::new(source) });
+                            }
+                        }
+                    }
+                }
+                "usemtl" => {
+                    let name = words.get(1).map_or("", |&(_, name)| name);
+                    match material_ids.get(name) {
+                        Some(&id) => material = Some(id),
+                        None => {
+                            model.warnings.push(ModelError::UnknownMaterial { at, name: name.to_string() });
+                            material = None;
+                        }
+                    }
+                }
                 _ => (),
             }
         }
-        
-        model.nverts = model.verts.len() as i32;
-        
-        assert_eq!(model.faces.len() as i32, model.nfaces);
+
+        attributes.check_indices(&polygons)?;
+        model.add_polygons(attributes, &polygons);
         Ok(model)
     }
 
-    fn add_line_float_vector(vec_to_append: &mut Vec<SVector<f32, 3>>, line: &str) {
-        let mut vector = Vec::new();
-        let line_vec = trim_whitespace(line);
-        for value in line_vec {
-            match value == "v" || value == "vt" || value == "vn" {
-                false => vector.push(value.parse::<f32>().unwrap()),
-                true => ()
+    /// Replaces the maps of every material with the given files, loaded
+    /// through `textures`.
+    pub fn override_textures(
+        &mut self,
+        diffuse: Option<&str>,
+        normal: Option<&str>,
+        specular: Option<&str>,
+        textures: &mut TextureCache,
+    ) -> Result<()> {
+        let diffuse = diffuse.map(|path| textures.load(path, ColorSpace::Srgb)).transpose()?;
+        let normal = normal.map(|path| textures.load(path, ColorSpace::Linear)).transpose()?;
+        let specular = specular.map(|path| textures.load(path, ColorSpace::Linear)).transpose()?;
+        for material in &mut self.materials {
+            material.diffuse_map = diffuse.clone().or(material.diffuse_map.take());
+            material.normal_map = normal.clone().or(material.normal_map.take());
+            material.specular_map = specular.clone().or(material.specular_map.take());
+        }
+        Ok(())
+    }
+
+    fn add_material(&mut self, material: Material) -> usize {
+        self.materials.push(material);
+        self.materials.len() - 1
+    }
+
+    /// Triangulates the parsed polygons into the vertex and index buffers,
+    /// filling in the texture coordinates and normals the file leaves out.
+    fn add_polygons(&mut self, mut attributes: Attributes, polygons: &[Polygon]) {
+        // Corners without texture coordinates sample the middle of the maps
+        let default_uv = attributes.uvs.len() as i32;
+        if polygons.iter().flat_map(|p| &p.corners).any(|c| c.uv.is_none()) {
+            attributes.uvs.push(Vector3::new(0.5, 0.5, 0.));
+        }
+
+        // Smooth normals: area-weighted average of the polygons around each vertex
+        let smooth_normals = attributes.norms.len() as i32;
+        if polygons.iter().flat_map(|p| &p.corners).any(|c| c.normal.is_none()) {
+            let mut sums: Vec<SVector<f32, 3>> = vec![Vector3::zeros(); attributes.verts.len()];
+            for polygon in polygons.iter().filter(|p| p.corners.iter().any(|c| c.normal.is_none())) {
+                let points: Vec<SVector<f32, 3>> = polygon.corners.iter().map(|c| attributes.verts[c.vert as usize]).collect();
+                let normal = newell_normal(&points);
+                for corner in &polygon.corners {
+                    sums[corner.vert as usize] += normal;
+                }
             }
+            attributes.norms.extend(sums.iter().map(|n| n.try_normalize(f32::EPSILON).unwrap_or_else(Vector3::z)));
         }
 
-        let vertex: SVector<f32, 3> = Vector3::new(vector[0], vector[1], vector[2]);
-
-        vec_to_append.push(vertex);
-    }
-
-    fn add_face_from_line(&mut self, face_line: &str) {
-        let mut face: Vec<i32> = Vec::new();
-        let mut face_texture: Vec<i32> = Vec::new();
-        let mut face_normal: Vec<i32> = Vec::new();
-        let mut vertex_info: Vec<&str>;
-        let mut vertex_num: i32;
-        let mut texture_num: i32;
-        let mut normal_num: i32;
-        for value in face_line.split(" ") {
-            match value == "f" {
-                false => {
-                    vertex_info = value.split("/").collect();
-                    vertex_num = (vertex_info[0].parse::<i32>().unwrap()) - 1;
-                    texture_num = (vertex_info[1].parse::<i32>().unwrap()) - 1;
-                    normal_num = (vertex_info[2].parse::<i32>().unwrap()) - 1;
-                    face.push(vertex_num);
-                    face_texture.push(texture_num);
-                    face_normal.push(normal_num);
-                },
-                true => ()
+        // One vertex per distinct (v, vt, vn) triple
+        let mut unique: HashMap<(i32, i32, i32), u32> = HashMap::new();
+        for polygon in polygons {
+            let points: Vec<SVector<f32, 3>> = polygon.corners.iter().map(|c| attributes.verts[c.vert as usize]).collect();
+            for tri in triangulate(&points) {
+                for corner in tri.map(|i| &polygon.corners[i]) {
+                    let key = (corner.vert, corner.uv.unwrap_or(default_uv), corner.normal.unwrap_or(smooth_normals + corner.vert));
+                    let index = *unique.entry(key).or_insert_with(|| {
+                        self.vertices.push(Vertex {
+                            position: attributes.verts[key.0 as usize],
+                            uv: attributes.uvs[key.1 as usize],
+                            normal: attributes.norms[key.2 as usize],
+                            tangent: Vector4::zeros(),
+                        });
+                        self.vertices.len() as u32 - 1
+                    });
+                    self.indices.push(index);
+                }
+                self.face_materials.push(polygon.material);
+                self.nfaces += 1;
             }
         }
-        self.faces.push(face);
-        self.faces_diffuse_coords.push(face_texture);
-        self.faces_normal_coords.push(face_normal);
-        self.nfaces += 1;
+        self.nverts = self.vertices.len() as i32;
+        self.compute_tangents();
+    }
+
+    /// Fills in the vertex tangents from the texture coordinates, averaging
+    /// the tangents of the faces around each vertex.
+    fn compute_tangents(&mut self) {
+        let mut tangents: Vec<SVector<f32, 3>> = vec![Vector3::zeros(); self.vertices.len()];
+        let mut bitangents: Vec<SVector<f32, 3>> = vec![Vector3::zeros(); self.vertices.len()];
+        for face in self.indices.chunks(3) {
+            let [a, b, c] = [0, 1, 2].map(|i| &self.vertices[face[i] as usize]);
+            let (e1, e2) = (b.position - a.position, c.position - a.position);
+            let (duv1, duv2) = (b.uv - a.uv, c.uv - a.uv);
+            let det = duv1.x * duv2.y - duv2.x * duv1.y;
+            if det.abs() < f32::EPSILON {
+                continue;
+            }
+            // Left unnormalized, so that larger faces weigh more
+            let t = (e1 * duv2.y - e2 * duv1.y) * det.signum();
+            let b = (e2 * duv1.x - e1 * duv2.x) * det.signum();
+            for &i in face {
+                tangents[i as usize] += t;
+                bitangents[i as usize] += b;
+            }
+        }
+
+        for (vertex, (t, b)) in self.vertices.iter_mut().zip(tangents.iter().zip(&bitangents)) {
+            let n = vertex.normal;
+            // Gram-Schmidt, with any direction orthogonal to `n` as a fallback
+            let t = (t - n * n.dot(t)).try_normalize(f32::EPSILON).unwrap_or_else(|| {
+                let axis = if n.x.abs() < 0.9 { Vector3::x() } else { Vector3::y() };
+                n.cross(&axis).normalize()
+            });
+            let w = if n.cross(&t).dot(b) < 0. { -1. } else { 1. };
+            vertex.tangent = Vector4::new(t.x, t.y, t.z, w);
+        }
+    }
+
+    /// Index into `vertices` of corner `nthvert` of face `iface`.
+    pub fn index(&self, iface: usize, nthvert: usize) -> usize {
+        self.indices[iface * 3 + nthvert] as usize
+    }
+
+    /// Position of corner `nthvert` of face `iface`.
+    pub fn vert(&self, iface: usize, nthvert: usize) -> SVector<f32, 3> {
+        self.vertices[self.index(iface, nthvert)].position
     }
 
+    /// Vertex normal of corner `nthvert` of face `iface`.
     pub fn uv_normal(&self, iface: usize, nthvert: usize) -> SVector<f32, 3> {
         // Normals should be taken from the normal map, just like the diffuse
-        let idx: i32 = self.faces_normal_coords[iface][nthvert];
-        return self.norms[idx as usize]
-    }
-
-    pub fn normal(&self, uvw: SVector<f32, 3>) -> SVector<f32, 3> {
-        let c = self.normal_map
-            .get_pixel(
-                (uvw[0] * (self.normal_map.width() as f32)) as u32,
-                ((1. - uvw[1]) * (self.normal_map.height() as f32)) as u32,
-            )
-            .to_rgb().0;
-        
-        let n: SVector<f32, 3> = Vector3::new(
-            c[2] as f32/255.*2. - 1.,
-            c[1] as f32/255.*2. - 1.,
-            c[0] as f32/255.*2. - 1.,
-        );
-        return n;
+        self.vertices[self.index(iface, nthvert)].normal
+    }
+
+    /// Material of face `iface`.
+    pub fn material(&self, iface: usize) -> &Material {
+        &self.materials[self.face_materials[iface]]
+    }
+
+    /// Normal map sample of face `iface` at texture coordinates `uvw`, or `None`
+    /// when its material has no normal map.
+    pub fn normal(&self, iface: usize, uvw: SVector<f32, 3>) -> Option<SVector<f32, 3>> {
+        self.material(iface).sample_normal(&TexCoords::new(uvw.xy()))
     }
 
-    pub fn diffuse(&self, uvw: SVector<f32, 3>) -> Rgb<u8> {
-        // Discard w coord and reverse one of the dimensions
-        let pixel_color = self.diffuse_map
-            .get_pixel(
-                (uvw[0] * (self.diffuse_map.width() as f32)) as u32,
-                ((1. - uvw[1]) * (self.diffuse_map.height() as f32)) as u32,
-            )
-            .to_rgb();
-        return pixel_color;
+    /// Diffuse color of face `iface` at texture coordinates `uvw`, in linear
+    /// light: `Kd` tinted by `map_Kd`.
+    pub fn diffuse(&self, iface: usize, uvw: SVector<f32, 3>) -> Color {
+        self.material(iface).sample_diffuse(&TexCoords::new(uvw.xy()))
     }
 
-    pub fn specular(&self, uvw: SVector<f32, 3>) -> f32 {
-        let s: u8 = self.specular_map
-            .get_pixel(
-                (uvw[0] * (self.specular_map.width() as f32)) as u32,
-                ((1. - uvw[1]) * (self.specular_map.height() as f32)) as u32,
-            )
-            .to_rgb().0[0];
-        
-        return s as f32;
+    /// Specular exponent of face `iface` at texture coordinates `uvw`.
+    pub fn specular(&self, iface: usize, uvw: SVector<f32, 3>) -> f32 {
+        self.material(iface).sample_specular(&TexCoords::new(uvw.xy()))
     }
 
+    /// Texture coordinates of corner `nthvert` of face `iface`.
     pub fn uv(&self, iface: usize, nthvert: usize) -> SVector<f32, 3> {
-        return self.uv_[self.faces_diffuse_coords[iface][nthvert] as usize]
+        self.vertices[self.index(iface, nthvert)].uv
+    }
+
+    /// Bounding box of the vertices, or `None` for an empty model.
+    pub fn aabb(&self) -> Option<Aabb> {
+        Aabb::from_points(self.vertices.iter().map(|v| v.position))
+    }
+
+    /// Sphere holding every vertex, or `None` for an empty model.
+    pub fn bounding_sphere(&self) -> Option<BoundingSphere> {
+        BoundingSphere::from_points(self.vertices.iter().map(|v| v.position))
     }
 }
 
+impl Attributes {
+    fn parse_face(&self, at: &Location, words: &[(usize, &str)]) -> Result<Vec<Corner>> {
+        if words.len() < 4 {
+            return Err(ModelError::MissingValue { at: at.clone(), keyword: "f".to_string(), expected: 3 });
+        }
+        let mut corners: Vec<Corner> = Vec::new();
+        for &(column, value) in &words[1..] {
+            let at = Location { column, ..at.clone() };
+            let bad_index = || ModelError::BadFaceIndex { at: at.clone(), token: value.to_string() };
+            let resolve = |info: &str, kind: &'static str, count: usize| -> Result<i32> {
+                match info.parse::<i32>() {
+                    Ok(i) if i > 0 => Ok(i - 1),
+                    Ok(i) if i < 0 && count as i32 + i >= 0 => Ok(count as i32 + i),
+                    Ok(i) if i < 0 => Err(ModelError::IndexOutOfRange { at: at.clone(), kind, index: i, count }),
+                    _ => Err(bad_index()),
+                }
+            };
+
+            let vertex_info: Vec<&str> = value.split('/').collect();
+            let (vert, uv, normal) = match vertex_info[..] {
+                [v] => (v, "", ""),
+                [v, vt] => (v, vt, ""),
+                [v, vt, vn] if !vn.is_empty() => (v, vt, vn),
+                _ => return Err(bad_index()),
+            };
+            corners.push(Corner {
+                vert: resolve(vert, "vertex", self.verts.len())?,
+                uv: if uv.is_empty() { None } else { Some(resolve(uv, "texture coordinate", self.uvs.len())?) },
+                normal: if normal.is_empty() { None } else { Some(resolve(normal, "normal", self.norms.len())?) },
+                at,
+            });
+        }
+        Ok(corners)
+    }
+
+    fn check_indices(&self, polygons: &[Polygon]) -> Result<()> {
+        for corner in polygons.iter().flat_map(|p| &p.corners) {
+            let references = [
+                ("vertex", Some(corner.vert), self.verts.len()),
+                ("texture coordinate", corner.uv, self.uvs.len()),
+                ("normal", corner.normal, self.norms.len()),
+            ];
+            for (kind, index, count) in references {
+                match index {
+                    Some(index) if index as usize >= count => {
+                        return Err(ModelError::IndexOutOfRange { at: corner.at.clone(), kind, index: index + 1, count });
+                    }
+                    _ => (),
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Splits a line into its whitespace-separated words.
 pub fn trim_whitespace(s: &str) -> Vec<&str> {
-    let words: Vec<&str> = s.split_whitespace().collect();
-    return words;
-}
\ No newline at end of file
+    s.split_whitespace().collect()
+}
+
+/// Like [`trim_whitespace`], pairing every word with its 1-based column.
+pub(crate) fn tokenize(s: &str) -> Vec<(usize, &str)> {
+    let s = s.split('#').next().unwrap_or_default();
+    trim_whitespace(s)
+        .into_iter()
+        .map(|word| (word.as_ptr() as usize - s.as_ptr() as usize + 1, word))
+        .collect()
+}
+
+/// Normal of a polygon by Newell's method, with a length of twice its area.
+fn newell_normal(points: &[SVector<f32, 3>]) -> SVector<f32, 3> {
+    let mut normal: SVector<f32, 3> = Vector3::zeros();
+    for (i, p) in points.iter().enumerate() {
+        normal += p.cross(&points[(i + 1) % points.len()]);
+    }
+    normal
+}
+
+/// Splits a polygon into triangles by ear clipping, so concave faces are
+/// handled too. Returns indices into `points` with the polygon's winding.
+fn triangulate(points: &[SVector<f32, 3>]) -> Vec<[usize; 3]> {
+    // Work in the coordinate plane the polygon is most aligned with
+    let normal = newell_normal(points);
+    let axis = normal.iamax();
+    let (a, b) = ((axis + 1) % 3, (axis + 2) % 3);
+    let sign = if normal[axis] < 0. { -1. } else { 1. };
+    let flat: Vec<(f32, f32)> = points.iter().map(|p| (p[a], p[b])).collect();
+    let area = |p: usize, q: usize, r: usize| {
+        let (p, q, r) = (flat[p], flat[q], flat[r]);
+        sign * ((q.0 - p.0) * (r.1 - p.1) - (q.1 - p.1) * (r.0 - p.0))
+    };
+
+    let mut remaining: Vec<usize> = (0..points.len()).collect();
+    let mut triangles: Vec<[usize; 3]> = Vec::new();
+    while remaining.len() > 3 {
+        let m = remaining.len();
+        let ear = (0..m).find(|&i| {
+            let (prev, cur, next) = (remaining[(i + m - 1) % m], remaining[i], remaining[(i + 1) % m]);
+            area(prev, cur, next) > 0.
+                && !remaining.iter().any(|&k| {
+                    k != prev && k != cur && k != next
+                        && area(prev, cur, k) >= 0. && area(cur, next, k) >= 0. && area(next, prev, k) >= 0.
+                })
+        });
+        let Some(i) = ear else {
+            break;
+        };
+        triangles.push([remaining[(i + m - 1) % m], remaining[i], remaining[(i + 1) % m]]);
+        remaining.remove(i);
+    }
+
+    // Whatever is left is a triangle, or a degenerate polygon we fan
+    for i in 1..remaining.len() - 1 {
+        triangles.push([remaining[0], remaining[i], remaining[i + 1]]);
+    }
+    triangles
+}
+
+/// Parses the values of a `v`, `vt` or `vn` statement; missing components after
+/// the first `required` ones default to 0.
+pub(crate) fn parse_float_vector(at: &Location, words: &[(usize, &str)], required: usize) -> Result<SVector<f32, 3>> {
+    let (keyword, values) = (words[0].1, &words[1..]);
+    if values.len() < required {
+        return Err(ModelError::MissingValue { at: at.clone(), keyword: keyword.to_string(), expected: required });
+    }
+
+    let mut vector: SVector<f32, 3> = Vector3::zeros();
+    for (i, &(column, value)) in values.iter().take(3).enumerate() {
+        vector[i] = match value.parse::<f32>() {
+            Ok(x) => x,
+            Err(_) => return Err(ModelError::MalformedNumber { at: Location { column, ..at.clone() }, token: value.to_string() }),
+        };
+    }
+    Ok(vector)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+    use super::*;
+
+    /// Writes `files`, pairs of names and contents, to a fresh directory
+    /// named after `test`, and returns it.
+    pub(crate) fn write_files(test: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rasterizer-{}-{}", std::process::id(), test));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        for (name, contents) in files {
+            fs::write(dir.join(name), contents).unwrap();
+        }
+        dir
+    }
+
+    /// Loads the OBJ file `contents` as `test.obj`, next to `files`.
+    fn load(test: &str, contents: &str, files: &[(&str, &str)]) -> Result<Model> {
+        let dir = write_files(test, &[files, &[("test.obj", contents)]].concat());
+        Model::from_file(&dir.join("test.obj").to_string_lossy())
+    }
+
+    fn attributes(verts: usize, uvs: usize, norms: usize) -> Attributes {
+        Attributes {
+            verts: vec![Vector3::zeros(); verts],
+            uvs: vec![Vector3::zeros(); uvs],
+            norms: vec![Vector3::zeros(); norms],
+        }
+    }
+
+    /// Vertex, texture coordinate and normal indices of a corner
+    type Indices = (i32, Option<i32>, Option<i32>);
+
+    fn parse_face(attributes: &Attributes, line: &str) -> Result<Vec<Indices>> {
+        let at = Location { path: "test.obj".to_string(), line: 1, column: 1 };
+        let corners = attributes.parse_face(&at, &tokenize(line))?;
+        Ok(corners.into_iter().map(|c| (c.vert, c.uv, c.normal)).collect())
+    }
+
+    #[test]
+    fn parse_face_forms() {
+        let attributes = attributes(4, 4, 4);
+        assert_eq!(parse_face(&attributes, "f 1 2 3").unwrap(), vec![(0, None, None), (1, None, None), (2, None, None)]);
+        assert_eq!(
+            parse_face(&attributes, "f 1/4 2/3 3/2").unwrap(),
+            vec![(0, Some(3), None), (1, Some(2), None), (2, Some(1), None)],
+        );
+        assert_eq!(
+            parse_face(&attributes, "f 1//2 2//3 3//4").unwrap(),
+            vec![(0, None, Some(1)), (1, None, Some(2)), (2, None, Some(3))],
+        );
+        assert_eq!(
+            parse_face(&attributes, "f 1/2/3 2/3/4 4/1/1 3/3/3").unwrap(),
+            vec![(0, Some(1), Some(2)), (1, Some(2), Some(3)), (3, Some(0), Some(0)), (2, Some(2), Some(2))],
+        );
+    }
+
+    #[test]
+    fn parse_face_negative_indices() {
+        let attributes = attributes(4, 2, 3);
+        assert_eq!(
+            parse_face(&attributes, "f -4/-2/-1 -3/-1/-2 -1//-3").unwrap(),
+            vec![(0, Some(0), Some(2)), (1, Some(1), Some(1)), (3, None, Some(0))],
+        );
+        assert!(matches!(
+            parse_face(&attributes, "f -5 1 2"),
+            Err(ModelError::IndexOutOfRange { kind: "vertex", index: -5, count: 4, .. }),
+        ));
+    }
+
+    #[test]
+    fn parse_face_errors() {
+        let attributes = attributes(3, 3, 3);
+        assert!(matches!(parse_face(&attributes, "f 1 2"), Err(ModelError::MissingValue { expected: 3, .. })));
+        for line in ["f 1 2 x", "f 1 2 0", "f 1 2 3//", "f 1 2 3/1/1/1"] {
+            assert!(matches!(parse_face(&attributes, line), Err(ModelError::BadFaceIndex { .. })), "{line}");
+        }
+        let Err(ModelError::BadFaceIndex { at, token }) = parse_face(&attributes, "f 1  2 3/x") else {
+            panic!("3/x parsed");
+        };
+        assert_eq!((at.column, token.as_str()), (8, "3/x"));
+    }
+
+    /// Twice the signed area of the triangle `t` of `points`, in the `xy` plane.
+    fn area(points: &[SVector<f32, 3>], t: [usize; 3]) -> f32 {
+        let [p, q, r] = t.map(|i| points[i]);
+        (q - p).xy().perp(&(r - p).xy())
+    }
+
+    #[test]
+    fn triangulate_concave_quads() {
+        // Arrowhead pointing up, with its reflex corner at index 3
+        let arrow = [Vector3::new(2., -1., 0.), Vector3::new(0., 3., 0.), Vector3::new(-2., -1., 0.), Vector3::new(0., 0., 0.)];
+        // Every starting corner and both windings
+        for start in 0..4 {
+            for reversed in [false, true] {
+                let mut points = arrow.to_vec();
+                points.rotate_left(start);
+                if reversed {
+                    points.reverse();
+                }
+                let sign = newell_normal(&points).z.signum();
+                let triangles = triangulate(&points);
+                assert_eq!(triangles.len(), 2);
+                let total: f32 = triangles.iter().map(|&t| {
+                    let a = area(&points, t);
+                    assert!(a * sign > 0., "{t:?} of {points:?} is flipped or flat");
+                    a.abs()
+                }).sum();
+                assert!((total - newell_normal(&points).z.abs()).abs() < 1e-5, "{triangles:?} of {points:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn malformed_numbers_point_at_their_column() {
+        let Err(error) = load("malformed_number", "v 0 0 0\nv 1  2.5 x3\n", &[]) else {
+            panic!("x3 parsed");
+        };
+        let ModelError::MalformedNumber { at, token } = &error else {
+            panic!("{error}");
+        };
+        assert_eq!((at.line, at.column, token.as_str()), (2, 10, "x3"));
+        assert!(error.to_string().ends_with("test.obj:2:10: malformed number `x3`"), "{error}");
+
+        let Err(ModelError::MalformedNumber { at, token }) = load("malformed_uv", "vt 0.5 .e\n", &[]) else {
+            panic!(".e parsed");
+        };
+        assert_eq!((at.line, at.column, token.as_str()), (1, 8, ".e"));
+    }
+
+    #[test]
+    fn out_of_range_indices_point_at_their_corner() {
+        let header = "v 0 0 0\nv 1 0 0\nv 0 1 0\nvt 0 0\nvn 0 0 1\n";
+        let cases = [
+            ("f 1 2 4", "vertex", 4, 3, 7),
+            ("f 1/1 2/2 3/1", "texture coordinate", 2, 1, 7),
+            ("f 1//1 2//1 3//2", "normal", 2, 1, 13),
+            ("f 1 2 -4", "vertex", -4, 3, 7),
+        ];
+        for (face, kind, index, count, column) in cases {
+            let result = load("out_of_range", &format!("{header}{face}\n"), &[]);
+            let Err(ModelError::IndexOutOfRange { at, kind: k, index: i, count: c }) = result else {
+                panic!("{face} loaded");
+            };
+            assert_eq!((at.line, at.column, k, i, c), (6, column, kind, index, count), "{face}");
+        }
+        // Faces may refer to elements defined after them
+        assert!(load("forward_reference", "f 1 2 3\nv 0 0 0\nv 1 0 0\nv 0 1 0\n", &[]).is_ok());
+    }
+
+    #[test]
+    fn missing_materials_fall_back_to_the_default() {
+        let obj = "\
+            mtllib missing.mtl  test.mtl
+            v 0 0 0
+            v 1 0 0
+            v 0 1 0
+            usemtl red
+            f 1 2 3
+            usemtl blue
+            f 1 2 3
+            f 3 2 1
+        ";
+        let model = load("missing_materials", obj, &[("test.mtl", "newmtl red\nKd 1 0 0\n")]).unwrap();
+        let names: Vec<&str> = (0..3).map(|face| model.material(face).name.as_str()).collect();
+        assert_eq!(names, ["red", "default", "default"]);
+
+        let [ModelError::MaterialLibrary { at: library, .. }, ModelError::UnknownMaterial { at, name }] = &model.warnings[..] else {
+            panic!("{:?}", model.warnings.iter().map(ToString::to_string).collect::<Vec<_>>());
+        };
+        assert_eq!((library.line, library.column), (1, 8));
+        assert_eq!((at.line, name.as_str()), (7, "blue"));
+    }
+}
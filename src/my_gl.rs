@@ -1,30 +1,82 @@
-use nalgebra::{Matrix4x1, SVector, SMatrix, Vector2, Vector3, Vector4, base, DVector};
-use image::{RgbImage, Rgb};
-use crate::model::Model;
-use crate::shaders::AnyShader;
-
-const DEPTH: f32 = 255.;
+use nalgebra::{Matrix4x1, SVector, SMatrix, Vector2, Vector3, Vector4};
+use std::ops::AddAssign;
+use rayon::prelude::*;
+use crate::depth::StencilOp;
+use crate::framebuffer::{Framebuffer, FragmentOutputs};
+use crate::model::{Model, Vertex};
+use crate::shaders::{Fragment, IShader, Varyings};
 
+/// Perspective matrix for a camera at distance `-1 / coeff` from the origin.
 pub fn projection(coeff: f32) -> SMatrix<f32, 4, 4> {
     // Coeff: -1. / (eye - center).z;
     let mut proj: SMatrix<f32, 4, 4> = SMatrix::identity();
     proj[(3, 2)] = coeff;
-    return proj;
+    proj
+}
+
+/// Perspective matrix of a camera looking down `-z` with the vertical field
+/// of view `fovy`, in radians, and `aspect` the width over the height.
+///
+/// The `near` and `far` planes go to `z = 1` and `z = -1`, the near and far
+/// sides of the depth range of [`viewport`].
+pub fn perspective(fovy: f32, aspect: f32, near: f32, far: f32) -> SMatrix<f32, 4, 4> {
+    let f = 1. / (fovy / 2.).tan();
+    let mut proj: SMatrix<f32, 4, 4> = SMatrix::zeros();
+    proj[(0, 0)] = f / aspect;
+    proj[(1, 1)] = f;
+    proj[(2, 2)] = (far + near) / (far - near);
+    proj[(2, 3)] = 2. * far * near / (far - near);
+    proj[(3, 2)] = -1.;
+    proj
 }
 
+/// Orthographic matrix of a camera looking down `-z`, seeing the `width` x
+/// `height` rectangle around its axis between the `near` and `far` planes,
+/// which go to `z = 1` and `z = -1` as with [`perspective`].
+pub fn orthographic(width: f32, height: f32, near: f32, far: f32) -> SMatrix<f32, 4, 4> {
+    let mut proj: SMatrix<f32, 4, 4> = SMatrix::identity();
+    proj[(0, 0)] = 2. / width;
+    proj[(1, 1)] = 2. / height;
+    proj[(2, 2)] = 2. / (far - near);
+    proj[(2, 3)] = (far + near) / (far - near);
+    proj
+}
 
+/// Shear of view space that slants depth into the picture: points `d` behind
+/// the plane at distance `focus` move by `factor * d` in the direction
+/// `angle` (in radians, counterclockwise from `x`), points in front of it the
+/// other way. Depth is unchanged, so the result goes through [`orthographic`]
+/// for an oblique projection.
+pub fn oblique(angle: f32, factor: f32, focus: f32) -> SMatrix<f32, 4, 4> {
+    let (dx, dy) = (-factor * angle.cos(), -factor * angle.sin());
+    let mut shear: SMatrix<f32, 4, 4> = SMatrix::identity();
+    shear[(0, 2)] = dx;
+    shear[(0, 3)] = dx * focus;
+    shear[(1, 2)] = dy;
+    shear[(1, 3)] = dy * focus;
+    shear
+}
+
+/// Maps normalized device coordinates to the `w` x `h` screen rectangle at (`x`, `y`).
+///
+/// Depth goes from `0` at `z = 1`, the side nearest to the camera with
+/// [`projection`], to `1` at `z = -1`.
 pub fn viewport(x: f32, y: f32, w: f32, h: f32) -> SMatrix<f32, 4, 4> {
     let mut m: SMatrix<f32, 4, 4> = SMatrix::identity();
     m[(0, 3)] = x + w / 2.;
     m[(1, 3)] = y + h / 2.;
-    m[(2, 3)] = DEPTH / 2.;
+    m[(2, 3)] = 0.5;
 
     m[(0, 0)] = w / 2.;
     m[(1, 1)] = h / 2.;
-    m[(2, 2)] = DEPTH / 2.;
-    return m;
+    m[(2, 2)] = -0.5;
+    m
 }
 
+/// View matrix of a camera at `eye` looking at `center`, for [`projection`].
+///
+/// It only translates by `-center`, leaving the distance to the eye to the
+/// projection; [`crate::camera::Camera::view`] is the general view matrix.
 pub fn lookat(eye: SVector<f32, 3>, center: SVector<f32, 3>, up: SVector<f32, 3>) -> SMatrix<f32, 4, 4> {
     let z: SVector<f32, 3> = (eye - center).normalize();
     let x: SVector<f32, 3> = up.cross(&z).normalize();
@@ -36,112 +88,727 @@ pub fn lookat(eye: SVector<f32, 3>, center: SVector<f32, 3>, up: SVector<f32, 3>
         res[(2, i)] = z[i];
         res[(i, 3)] = -center[i];
     }
-    return res;
+    res
 }
 
+/// Homogeneous column matrix of a point.
 pub fn v2m(v: SVector<f32, 3>) -> SMatrix<f32, 4, 1> {
-    let m = Matrix4x1::from_column_slice(&[v.x, v.y, v.z, 1.]);
-    return m;
+    Matrix4x1::from_column_slice(&[v.x, v.y, v.z, 1.])
 }
 
+/// Perspective divide of a homogeneous column matrix.
 pub fn m2v(m: SMatrix<f32, 4, 1>) -> SVector<f32, 4> {
-    let v = Vector4::new(
+    Vector4::new(
         m[(0, 0)] / m[(3, 0)],
         m[(1, 0)] / m[(3, 0)],
         m[(2, 0)] / m[(3, 0)],
-        m[(3, 0)] / m[(3, 0)],
-    );
-    return v;
+        1.,
+    )
 }
 
+/// Like [`m2v`], rounding the result down to whole pixels.
 pub fn m2v_floor(m: SMatrix<f32, 4, 1>) -> SVector<f32, 4> {
-    let v = Vector4::new(
+    Vector4::new(
         (m[(0, 0)] / m[(3, 0)]).floor(),
         (m[(1, 0)] / m[(3, 0)]).floor(),
         (m[(2, 0)] / m[(3, 0)]).floor(),
-        (m[(3, 0)] / m[(3, 0)]).floor(),
-    );
-    return v;
+        1.,
+    )
 }
 
+/// Drops the last component of a 4-vector.
 pub fn proj4_3(v: SVector<f32, 4>) -> SVector<f32, 3> {
     // TODO: Make this function general for any input and output sizes
     Vector3::new(
-        v[(0)],
-        v[(1)],
-        v[(2)]
+        v[0],
+        v[1],
+        v[2]
     )
 }
 
+/// A plane of clip space: points `p` with `normal · p + offset >= 0` are kept.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ClipPlane {
+    pub normal: SVector<f32, 4>,
+    pub offset: f32,
+}
+
+impl ClipPlane {
+    /// Keeps points with `w >= near`. With a perspective projection `w` grows
+    /// with the distance to the camera, so this removes what is behind it.
+    pub fn near(near: f32) -> Self {
+        ClipPlane { normal: Vector4::new(0., 0., 0., 1.), offset: -near }
+    }
+
+    /// Keeps points with `w <= far`.
+    pub fn far(far: f32) -> Self {
+        ClipPlane { normal: Vector4::new(0., 0., 0., -1.), offset: far }
+    }
+
+    /// The six planes of the canonical view volume `-w <= x, y, z <= w`.
+    pub fn frustum() -> [ClipPlane; 6] {
+        [
+            Vector4::new(1., 0., 0., 1.),
+            Vector4::new(-1., 0., 0., 1.),
+            Vector4::new(0., 1., 0., 1.),
+            Vector4::new(0., -1., 0., 1.),
+            Vector4::new(0., 0., 1., 1.),
+            Vector4::new(0., 0., -1., 1.),
+        ].map(|normal| ClipPlane { normal, offset: 0. })
+    }
+
+    /// Signed distance of `p` to the plane, positive on the kept side.
+    pub fn distance(&self, p: &SVector<f32, 4>) -> f32 {
+        self.normal.dot(p) + self.offset
+    }
+}
+
+/// Which way the corners of a face go around on the screen, with `y` up.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Winding {
+    Clockwise,
+    CounterClockwise,
+}
 
-fn barycentric(pts: &Vec<SVector<f32, 4>>, p: SVector<f32, 3>) -> SVector<f32, 3> {
-    let v1: SVector<f32, 3> = Vector3::new(
-        pts[2][0] - pts[0][0],
-        pts[1][0] - pts[0][0],
-        pts[0][0] - p[0],
-    );
-    let v2: SVector<f32, 3> = Vector3::new(
-        pts[2][1] - pts[0][1],
-        pts[1][1] - pts[0][1],
-        pts[0][1] - p[1],
-    );
+/// Fixed-function state of the pipeline around the shaders.
+pub struct Pipeline {
+    /// Maps normalized device coordinates to the screen, see [`viewport`]
+    pub viewport: SMatrix<f32, 4, 4>,
+    /// Planes every triangle is clipped against before rasterization, along
+    /// with the guard band
+    pub clip_planes: Vec<ClipPlane>,
+    /// Winding of the faces to drop, usually the back faces: `Clockwise` for
+    /// models whose front faces are counterclockwise, as in OBJ files
+    pub cull: Option<Winding>,
+    /// Skip models whose bounding box is out of the `x` and `y` range of the
+    /// view volume or behind a clip plane, which assumes the vertex shader
+    /// moves positions by an affine map
+    pub frustum_culling: bool,
+    /// Side in pixels of the square tiles rasterized in parallel
+    pub tile_size: u32,
+}
 
-    let u = v1.cross(&v2);
+impl Pipeline {
+    /// Pipeline clipping against a near plane in front of the camera, at 5%
+    /// of the distance to the point it looks at with [`projection`].
+    pub fn new(viewport: SMatrix<f32, 4, 4>) -> Self {
+        Pipeline { viewport, clip_planes: vec![ClipPlane::near(0.05)], cull: None, frustum_culling: true, tile_size: 64 }
+    }
+
+    /// Planes `|x|, |y| <= k w` of clip space keeping what is drawn within
+    /// half the guard band of the screen, with room for rounding, so that
+    /// [`triangle`] never clamps a vertex and changes the shape of a face.
+    /// They also remove what has `w < 0`.
+    pub fn guard_band(&self) -> [ClipPlane; 4] {
+        // Screen `x` is `viewport[(0, 0)] * x / w + viewport[(0, 3)]`, likewise for `y`
+        let k = |axis: usize| {
+            let (scale, offset) = (self.viewport[(axis, axis)].abs(), self.viewport[(axis, 3)].abs());
+            ((GUARD_BAND / 2. - offset) / scale.max(f32::EPSILON)).max(1.)
+        };
+        let (kx, ky) = (k(0), k(1));
+        [
+            Vector4::new(1., 0., 0., kx),
+            Vector4::new(-1., 0., 0., kx),
+            Vector4::new(0., 1., 0., ky),
+            Vector4::new(0., -1., 0., ky),
+        ].map(|normal| ClipPlane { normal, offset: 0. })
+    }
 
-    if u.z.abs() < 1e-2 {
-        return Vector3::new(-1., 1., 1.);
+    /// Whether the shaded bounding box `corners` may be seen, that is none of
+    /// the planes has them all on its outer side.
+    fn may_see(&self, corners: &[SVector<f32, 4>]) -> bool {
+        let [left, right, bottom, top, ..] = ClipPlane::frustum();
+        [left, right, bottom, top].iter().chain(&self.clip_planes)
+            .all(|plane| corners.iter().any(|p| plane.distance(p) >= 0.))
     }
 
-    return Vector3::new(1.0f32 - (u.x + u.y) / u.z, u.y / u.z, u.x / u.z);
+    /// Screen position of the clip-space point `p`, keeping its clip `w`.
+    pub fn to_screen(&self, p: &SVector<f32, 4>) -> SVector<f32, 4> {
+        let v = m2v(self.viewport * p);
+        Vector4::new(v.x, v.y, v.z, p.w)
+    }
+}
+
+/// Clips a clip-space triangle against `planes` (Sutherland–Hodgman).
+///
+/// Returns the clipped convex polygon, empty if nothing is left. Each vertex
+/// comes with its barycentric coordinates in the original triangle, which is
+/// what the varyings are interpolated with.
+pub fn clip_triangle(pts: &[SVector<f32, 4>], planes: &[ClipPlane]) -> Vec<(SVector<f32, 4>, SVector<f32, 3>)> {
+    let mut polygon = vec![(pts[0], Vector3::x()), (pts[1], Vector3::y()), (pts[2], Vector3::z())];
+    for plane in planes {
+        let mut clipped = Vec::with_capacity(polygon.len() + 1);
+        for (i, &(a, bar_a)) in polygon.iter().enumerate() {
+            let (b, bar_b) = polygon[(i + 1) % polygon.len()];
+            let (da, db) = (plane.distance(&a), plane.distance(&b));
+            if da >= 0. {
+                clipped.push((a, bar_a));
+            }
+            if (da >= 0.) != (db >= 0.) {
+                let t = da / (da - db);
+                clipped.push((a + (b - a) * t, bar_a + (bar_b - bar_a) * t));
+            }
+        }
+        polygon = clipped;
+    }
+    polygon
+}
+
+/// Faces of a model and what became of them in [`draw`].
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct DrawStats {
+    pub faces: usize,
+    /// Faces skipped with the whole model, out of view
+    pub frustum_culled: usize,
+    /// Faces dropped for their winding
+    pub back_face_culled: usize,
+    /// Faces with nothing left after clipping
+    pub clipped: usize,
 }
 
-pub fn triangle(
-    pts: Vec<SVector<f32, 4>>,
+impl AddAssign for DrawStats {
+    fn add_assign(&mut self, rhs: DrawStats) {
+        self.faces += rhs.faces;
+        self.frustum_culled += rhs.frustum_culled;
+        self.back_face_culled += rhs.back_face_culled;
+        self.clipped += rhs.clipped;
+    }
+}
+
+impl DrawStats {
+    /// Faces that went on to rasterization.
+    pub fn drawn(&self) -> usize {
+        self.faces - self.frustum_culled - self.back_face_culled - self.clipped
+    }
+}
+
+/// A triangle ready for rasterization, in screen coordinates.
+#[derive(Clone, Debug)]
+pub struct Primitive<V> {
+    /// Screen position of the corners, with their clip-space `w` as fourth component
+    pub pts: [SVector<f32, 4>; 3],
+    /// Barycentric coordinates of `pts` in the face, columnwise; they differ
+    /// from the identity once the face is clipped
+    pub varying_bar: SMatrix<f32, 3, 3>,
+    /// Varyings of the corners of the face
+    pub varyings: [V; 3],
+    /// Index of the face in the model
+    pub face: usize,
+}
+
+/// A rectangle of the screen with its own copy of the framebuffer below it,
+/// so that tiles can be rasterized independently.
+pub struct Tile {
+    pub x: u32,
+    pub y: u32,
+    pub framebuffer: Framebuffer,
+}
+
+impl Tile {
+    /// Copies the `width` x `height` region at (`x`, `y`) of `framebuffer`.
+    pub fn new(x: u32, y: u32, width: u32, height: u32, framebuffer: &Framebuffer) -> Self {
+        Tile { x, y, framebuffer: framebuffer.region(x, y, width, height) }
+    }
+
+    /// Copies the tile back where it was taken from.
+    pub fn write_back(&self, framebuffer: &mut Framebuffer) {
+        framebuffer.copy_from(&self.framebuffer, self.x, self.y);
+    }
+}
+
+/// Screen bounding box of a triangle, clamped to the `min`..=`max` pixel range.
+fn bounding_box(pts: &[SVector<f32, 4>], min: SVector<f32, 2>, max: SVector<f32, 2>) -> (SVector<f32, 2>, SVector<f32, 2>) {
+    let mut bboxmin: SVector<f32, 2> = Vector2::new(f32::MAX, f32::MAX);
+    let mut bboxmax: SVector<f32, 2> = Vector2::new(-f32::MAX, -f32::MAX);
+
+    for pt in pts.iter().take(3) {
+        for j in 0..=1 {
+            bboxmin[j] = f32::max(min[j], f32::min(bboxmin[j], pt[j]));
+            bboxmax[j] = f32::min(max[j], f32::max(bboxmax[j], pt[j]));
+        }
+    }
+    (bboxmin, bboxmax)
+}
+
+/// Fractional bits of the fixed-point screen coordinates.
+pub const SUBPIXEL_BITS: u32 = 8;
+const SUBPIXEL: i64 = 1 << SUBPIXEL_BITS;
+/// Vertices are kept within this many pixels of the origin, so that edge
+/// functions cannot overflow; see [`Pipeline::guard_band`].
+const GUARD_BAND: f32 = (1 << 20) as f32;
+
+/// Edge function of `a` -> `b` in fixed point: positive for points on its left.
+#[derive(Copy, Clone)]
+struct Edge {
+    /// Value at the first sampled pixel center
+    value: i64,
+    step_x: i64,
+    step_y: i64,
+}
+
+impl Edge {
+    fn new(a: (i64, i64), b: (i64, i64), start: (i64, i64)) -> Self {
+        let (dx, dy) = (b.0 - a.0, b.1 - a.1);
+        // Top-left rule: samples exactly on an edge belong to the triangle only
+        // if the edge is a top or a left one, so shared edges are drawn once
+        let top_left = dy < 0 || (dy == 0 && dx < 0);
+        Edge {
+            value: dx * (start.1 - a.1) - dy * (start.0 - a.0) - if top_left { 0 } else { 1 },
+            step_x: -dy * SUBPIXEL,
+            step_y: dx * SUBPIXEL,
+        }
+    }
+}
+
+/// Rasterizes the part of `primitive` that falls in `tile`, shading it with
+/// `shader`.
+///
+/// Covered pixels go through the stencil test, then the depth test of the
+/// framebuffer when it has these buffers. Fragments that pass both and are
+/// not discarded store their depth and shader outputs.
+///
+/// Pixels are sampled at their centers against edge functions evaluated
+/// incrementally in fixed point with [`SUBPIXEL_BITS`] of precision, and the
+/// top-left rule decides samples on an edge, so triangles sharing an edge
+/// leave no gaps and never cover a pixel twice.
+///
+/// The varyings are interpolated with barycentric coordinates corrected for
+/// perspective with the clip-space `w` of the corners.
+pub fn triangle<S: IShader>(
+    primitive: &Primitive<S::Varyings>,
     model: &Model,
-    shader: &AnyShader,
-    zbuffer: &mut Vec<f32>,
-    image: &mut RgbImage,
-    color: Rgb<u8>
+    shader: &S,
+    uniforms: &S::Uniforms,
+    tile: &mut Tile,
 ) {
-    let (imwidth, imheight) = (image.width() as f32, image.height() as f32);
+    let pts = &primitive.pts;
+    // Guard band clipping keeps the corners well within the clamp
+    let fixed = |v: f32| (v.clamp(-GUARD_BAND, GUARD_BAND) * SUBPIXEL as f32).round() as i64;
+    let mut v: [(i64, i64); 3] = [0, 1, 2].map(|i| (fixed(pts[i][0]), fixed(pts[i][1])));
 
-    let mut bboxmin: SVector<f32, 2> = Vector2::new(std::f32::MAX, std::f32::MAX);
-    let mut bboxmax: SVector<f32, 2> = Vector2::new(-std::f32::MAX, -std::f32::MAX);
-    let clamp: SVector<f32, 2> = Vector2::new(imwidth - 1., imheight - 1.);
+    // Walk the corners counterclockwise; `order` maps them back to `pts`
+    let area = |v: &[(i64, i64); 3]| (v[1].0 - v[0].0) * (v[2].1 - v[0].1) - (v[1].1 - v[0].1) * (v[2].0 - v[0].0);
+    let mut order = [0, 1, 2];
+    if area(&v) < 0 {
+        v.swap(1, 2);
+        order.swap(1, 2);
+    }
+    let area = area(&v);
+    if area == 0 {
+        return;
+    }
 
-    for i in 0..3 {
-        for j in 0..=1 {
-            bboxmin[j] = f32::max(0., f32::min(bboxmin[j], pts[i][j]));
-            bboxmax[j] = f32::min(clamp[j], f32::max(bboxmax[j], pts[i][j]));
+    // Pixel centers inside the bounding box, clamped to the tile
+    let (tile_w, tile_h) = (tile.framebuffer.width() as i64, tile.framebuffer.height() as i64);
+    let (ox, oy) = (tile.x as i64, tile.y as i64);
+    let first = |c: i64| (c - SUBPIXEL / 2 + SUBPIXEL - 1).div_euclid(SUBPIXEL);
+    let last = |c: i64| (c - SUBPIXEL / 2).div_euclid(SUBPIXEL);
+    let xmin = v.iter().map(|p| first(p.0)).min().unwrap().max(ox);
+    let xmax = v.iter().map(|p| last(p.0)).max().unwrap().min(ox + tile_w - 1);
+    let ymin = v.iter().map(|p| first(p.1)).min().unwrap().max(oy);
+    let ymax = v.iter().map(|p| last(p.1)).max().unwrap().min(oy + tile_h - 1);
+    if xmin > xmax || ymin > ymax {
+        return;
+    }
+
+    // Each edge function weighs the corner opposite to it
+    let start = (xmin * SUBPIXEL + SUBPIXEL / 2, ymin * SUBPIXEL + SUBPIXEL / 2);
+    let mut rows = [Edge::new(v[1], v[2], start), Edge::new(v[2], v[0], start), Edge::new(v[0], v[1], start)];
+    let mut outputs = FragmentOutputs::default();
+
+    // Screen-space barycentric coordinates of `pts` for the given edge values
+    let screen_bar = |values: [i64; 3]| {
+        let mut bc_screen: SVector<f32, 3> = Vector3::zeros();
+        for (k, &i) in order.iter().enumerate() {
+            bc_screen[i] = (values[k] as f64 / area as f64) as f32;
+        }
+        bc_screen
+    };
+    // Varyings for screen-space barycentric coordinates, correcting for perspective
+    let varyings_at = |bc_screen: SVector<f32, 3>| {
+        let bc_clip: SVector<f32, 3> = Vector3::new(
+            bc_screen.x / pts[0][3],
+            bc_screen.y / pts[1][3],
+            bc_screen.z / pts[2][3],
+        );
+        let bar = primitive.varying_bar * (bc_clip / (bc_clip.x + bc_clip.y + bc_clip.z));
+        S::Varyings::interpolate(&primitive.varyings, &bar)
+    };
+
+    for y in ymin..=ymax {
+        let mut edges = rows;
+        for x in xmin..=xmax {
+            if edges.iter().all(|e| e.value >= 0) {
+                let bc_screen = screen_bar(edges.map(|e| e.value));
+
+                let z = pts[0][2] * bc_screen.x + pts[1][2] * bc_screen.y + pts[2][2] * bc_screen.z;
+                let (px, py) = ((x - ox) as u32, (y - oy) as u32);
+                let fb = &mut tile.framebuffer;
+                let frag_depth: f32 = fb.depth.as_ref().map_or(z, |d| d.window_depth(z));
+                let stencil_pass = fb.stencil.as_ref().is_none_or(|s| s.test(px, py));
+                let depth_pass = stencil_pass && fb.depth.as_ref().is_none_or(|d| d.test(px, py, frag_depth));
+                let stencil_op = fb.stencil.as_ref().map_or(StencilOp::Keep, |s| match (stencil_pass, depth_pass) {
+                    (false, _) => s.fail,
+                    (true, false) => s.depth_fail,
+                    (true, true) => s.pass,
+                });
+
+                // Failed fragments are only shaded to know if they update the stencil
+                if depth_pass || stencil_op != StencilOp::Keep {
+                    let fragment = Fragment {
+                        position: Vector3::new(x as f32 + 0.5, y as f32 + 0.5, frag_depth),
+                        face: primitive.face,
+                        material: model.material(primitive.face),
+                        bar_screen: primitive.varying_bar * bc_screen,
+                        varyings: varyings_at(bc_screen),
+                        varyings_dx: varyings_at(screen_bar(edges.map(|e| e.value + e.step_x))),
+                        varyings_dy: varyings_at(screen_bar(edges.map(|e| e.value + e.step_y))),
+                    };
+
+                    outputs.clear();
+                    if shader.fragment(uniforms, &fragment, &mut outputs) {
+                        if let Some(stencil) = &mut fb.stencil {
+                            stencil.apply(px, py, stencil_op);
+                        }
+                        if depth_pass {
+                            if let Some(depth) = &mut fb.depth {
+                                depth.update(px, py, frag_depth);
+                            }
+                            fb.write(px, py, &outputs);
+                        }
+                    }
+                }
+            }
+            for e in edges.iter_mut() {
+                e.value += e.step_x;
+            }
+        }
+        for e in rows.iter_mut() {
+            e.value += e.step_y;
+        }
+    }
+}
+
+/// Draws every face of `model` into `framebuffer`, see [`triangle`] for what
+/// happens to each fragment, and counts what became of the faces.
+///
+/// With [`Pipeline::frustum_culling`], the corners of the bounding box of the
+/// model go through the vertex shader first, and nothing is drawn if they are
+/// all out of view. Otherwise the vertex shader takes the model to clip
+/// space, where faces are clipped against the planes of `pipeline` and its
+/// [`Pipeline::guard_band`], then dropped if their winding on the screen is
+/// [`Pipeline::cull`], before being rasterized. Shaded vertices are kept in a
+/// post-transform cache indexed like `model.vertices`, so each vertex goes
+/// through [`IShader::vertex`] once.
+///
+/// Faces are set up in order, then binned into tiles that are rasterized on
+/// the rayon thread pool. Every tile draws its triangles in face order, so the
+/// image does not depend on the number of threads.
+pub fn draw<S: IShader + Sync>(
+    model: &Model,
+    shader: &S,
+    uniforms: &S::Uniforms,
+    pipeline: &Pipeline,
+    framebuffer: &mut Framebuffer,
+) -> DrawStats where S::Uniforms: Sync {
+    let mut stats = DrawStats { faces: model.nfaces as usize, ..DrawStats::default() };
+    if pipeline.frustum_culling {
+        if let Some(aabb) = model.aabb() {
+            let corners = aabb.corners().map(|position| {
+                let vertex = Vertex { position, uv: Vector3::zeros(), normal: Vector3::zeros(), tangent: Vector4::zeros() };
+                shader.vertex(uniforms, &vertex).0
+            });
+            if !pipeline.may_see(&corners) {
+                stats.frustum_culled = stats.faces;
+                return stats;
+            }
+        }
+    }
+
+    let clip_planes: Vec<ClipPlane> = pipeline.clip_planes.iter().copied().chain(pipeline.guard_band()).collect();
+    let mut cache: Vec<Option<(SVector<f32, 4>, S::Varyings)>> = vec![None; model.nverts as usize];
+    let mut primitives: Vec<Primitive<S::Varyings>> = Vec::new();
+    for i in 0..model.nfaces as usize {
+        let [a, b, c] = [0, 1, 2].map(|j| {
+            let ivert = model.index(i, j);
+            *cache[ivert].get_or_insert_with(|| shader.vertex(uniforms, &model.vertices[ivert]))
+        });
+
+        let polygon = clip_triangle(&[a.0, b.0, c.0], &clip_planes);
+        if polygon.len() < 3 {
+            stats.clipped += 1;
+            continue;
+        }
+        let screen: Vec<SVector<f32, 4>> = polygon.iter().map(|(p, _)| pipeline.to_screen(p)).collect();
+        // Twice the signed area, positive when counterclockwise
+        let area: f32 = (0..screen.len()).map(|k| {
+            let (p, q) = (screen[k], screen[(k + 1) % screen.len()]);
+            p.x * q.y - q.x * p.y
+        }).sum();
+        let winding = match area {
+            a if a > 0. => Some(Winding::CounterClockwise),
+            a if a < 0. => Some(Winding::Clockwise),
+            _ => None,
+        };
+        if winding.is_some() && winding == pipeline.cull {
+            stats.back_face_culled += 1;
+            continue;
+        }
+        for k in 1..polygon.len() - 1 {
+            let corners = [polygon[0], polygon[k], polygon[k + 1]];
+            primitives.push(Primitive {
+                pts: [screen[0], screen[k], screen[k + 1]],
+                varying_bar: SMatrix::from_columns(&corners.map(|(_, bar)| bar)),
+                varyings: [a.1, b.1, c.1],
+                face: i,
+            });
+        }
+    }
+
+    let (width, height) = (framebuffer.width(), framebuffer.height());
+    let size = pipeline.tile_size.max(1);
+    let (tiles_x, tiles_y) = (width.div_ceil(size), height.div_ceil(size));
+    let mut bins: Vec<Vec<&Primitive<S::Varyings>>> = vec![Vec::new(); (tiles_x * tiles_y) as usize];
+    for primitive in &primitives {
+        let (bboxmin, bboxmax) = bounding_box(&primitive.pts, Vector2::zeros(), Vector2::new(width as f32 - 1., height as f32 - 1.));
+        if bboxmin.x > bboxmax.x || bboxmin.y > bboxmax.y {
+            continue;
+        }
+        for ty in bboxmin.y as u32 / size..=bboxmax.y as u32 / size {
+            for tx in bboxmin.x as u32 / size..=bboxmax.x as u32 / size {
+                bins[(ty * tiles_x + tx) as usize].push(primitive);
+            }
+        }
+    }
+
+    let tiles: Vec<Tile> = bins
+        .par_iter()
+        .enumerate()
+        .filter(|(_, bin)| !bin.is_empty())
+        .map(|(t, bin)| {
+            let (x, y) = (t as u32 % tiles_x * size, t as u32 / tiles_x * size);
+            let mut tile = Tile::new(x, y, size.min(width - x), size.min(height - y), framebuffer);
+            for primitive in bin {
+                triangle(primitive, model, shader, uniforms, &mut tile);
+            }
+            tile
+        })
+        .collect();
+
+    for tile in tiles {
+        tile.write_back(framebuffer);
+    }
+    stats
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::color::Color;
+    use crate::depth::StencilBuffer;
+    use crate::framebuffer::ColorFormat;
+    use crate::material::Material;
+    use crate::model::Vertex;
+
+    /// Shader keeping every fragment of faces, without writing any output.
+    struct FlatShader;
+
+    impl IShader for FlatShader {
+        /// Takes the model to clip space
+        type Uniforms = SMatrix<f32, 4, 4>;
+        type Varyings = ();
+
+        fn vertex(&self, transformation: &SMatrix<f32, 4, 4>, vertex: &Vertex) -> (SVector<f32, 4>, ()) {
+            (transformation * v2m(vertex.position), ())
+        }
+
+        fn fragment(&self, _uniforms: &SMatrix<f32, 4, 4>, _fragment: &Fragment<()>, _out: &mut FragmentOutputs) -> bool {
+            true
         }
     }
 
-    let mut p: SVector<f32, 3> = Vector3::new(bboxmin.x, bboxmin.y, 0.);
+    /// Shader writing the screen-space barycentrics of the fragment, then its
+    /// interpolated texture coordinates. Positions are NDC x and y with the
+    /// clip w in z.
+    struct BarShader;
 
-    while p.x <= bboxmax.x {
-        p.y = bboxmin.y;
-        while p.y <= bboxmax.y {
-            let bc_screen: SVector<f32, 3> = barycentric(&pts, p);
+    impl IShader for BarShader {
+        type Uniforms = ();
+        type Varyings = SVector<f32, 3>;
 
-            p.z = pts[0][2] * bc_screen.x + pts[1][2] * bc_screen.y + pts[2][2] * bc_screen.z;
-            let w: f32 = pts[0][3]*bc_screen.x + pts[1][3]*bc_screen.y + pts[2][3]*bc_screen.z;
+        fn vertex(&self, _uniforms: &(), vertex: &Vertex) -> (SVector<f32, 4>, SVector<f32, 3>) {
+            let p = vertex.position;
+            (Vector4::new(p.x * p.z, p.y * p.z, 0., p.z), vertex.uv)
+        }
 
-            let frag_depth: f32 = f32::max(0., f32::min(255., p.z / w + 0.5));
+        fn fragment(&self, _uniforms: &(), fragment: &Fragment<SVector<f32, 3>>, out: &mut FragmentOutputs) -> bool {
+            let (b, v) = (fragment.bar_screen, fragment.varyings);
+            out.set(0, Color::new(b.x, b.y, b.z, 1.));
+            out.set(1, Color::new(v.x, v.y, v.z, 1.));
+            true
+        }
+    }
+
+    /// Model of the triangles `faces` between the clip-space points `positions`.
+    fn mesh(positions: &[SVector<f32, 4>], faces: &[[u32; 3]]) -> Model {
+        let vertices: Vec<Vertex> = positions.iter().map(|p| Vertex {
+            position: proj4_3(*p) / p.w,
+            uv: Vector3::zeros(),
+            normal: Vector3::z(),
+            tangent: Vector4::zeros(),
+        }).collect();
+        Model {
+            nfaces: faces.len() as i32,
+            nverts: vertices.len() as i32,
+            vertices,
+            indices: faces.concat(),
+            materials: vec![Material::default()],
+            face_materials: vec![0; faces.len()],
+            warnings: Vec::new(),
+        }
+    }
 
-            if bc_screen.x < 0. || bc_screen.y < 0. || bc_screen.z < 0. || zbuffer[(p.x + p.y * imwidth) as usize] > frag_depth {
-                p.y += 1.;
-                continue;
+    /// Number of times every pixel of a `size` square is covered by `model`,
+    /// whose positions are already in clip space.
+    fn coverage(model: &Model, size: u32, tile_size: u32) -> StencilBuffer {
+        let mut framebuffer = Framebuffer::new(size, size);
+        let mut stencil = StencilBuffer::new(size, size);
+        stencil.pass = StencilOp::Increment;
+        framebuffer.stencil = Some(stencil);
+        let pipeline = Pipeline { tile_size, ..Pipeline::new(viewport(0., 0., size as f32, size as f32)) };
+        draw(model, &FlatShader, &SMatrix::identity(), &pipeline, &mut framebuffer);
+        framebuffer.stencil.unwrap()
+    }
+
+    #[test]
+    fn shared_edges_cover_every_pixel_once() {
+        // Grid over the whole screen, its inner corners moved around, some
+        // onto pixel centers so that edges go through them
+        const SIZE: u32 = 64;
+        const CELLS: u32 = 4;
+        let jitter = [(0.5, 0.5), (3.25, -2.5), (-4.5, 0.5), (0.5, 5.75), (-1.5, -3.5)];
+        let mut positions = Vec::new();
+        for j in 0..=CELLS {
+            for i in 0..=CELLS {
+                let (mut x, mut y) = ((i * SIZE / CELLS) as f32, (j * SIZE / CELLS) as f32);
+                if i > 0 && i < CELLS && j > 0 && j < CELLS {
+                    let (dx, dy) = jitter[((i * 3 + j) % 5) as usize];
+                    (x, y) = (x + dx, y + dy);
+                }
+                positions.push(Vector4::new(x / SIZE as f32 * 2. - 1., y / SIZE as f32 * 2. - 1., 0., 1.));
+            }
+        }
+        let mut faces = Vec::new();
+        for j in 0..CELLS {
+            for i in 0..CELLS {
+                let corner = |di: u32, dj: u32| (j + dj) * (CELLS + 1) + i + di;
+                let (a, b, c, d) = (corner(0, 0), corner(1, 0), corner(1, 1), corner(0, 1));
+                // Both diagonals and both windings
+                if (i + j) % 2 == 0 {
+                    faces.extend([[a, b, c], [a, d, c]]);
+                } else {
+                    faces.extend([[a, b, d], [b, c, d]]);
+                }
             }
+        }
+
+        let model = mesh(&positions, &faces);
+        for tile_size in [64, 7] {
+            let stencil = coverage(&model, SIZE, tile_size);
+            assert!(stencil.as_slice().iter().all(|&n| n == 1), "tiles of {tile_size}: {:?}", stencil.as_slice());
+        }
+    }
+
+    #[test]
+    fn clip_triangle_keeps_inside_triangles() {
+        let pts = [Vector4::new(-0.5, -0.5, 0., 1.), Vector4::new(0.5, -0.5, 0., 1.), Vector4::new(0., 0.5, 0., 1.)];
+        let polygon = clip_triangle(&pts, &ClipPlane::frustum());
+        assert_eq!(polygon, vec![(pts[0], Vector3::x()), (pts[1], Vector3::y()), (pts[2], Vector3::z())]);
+    }
+
+    #[test]
+    fn clip_triangle_drops_outside_triangles() {
+        let pts = [Vector4::new(2., -0.5, 0., 1.), Vector4::new(3., -0.5, 0., 1.), Vector4::new(2., 0.5, 0., 1.)];
+        assert!(clip_triangle(&pts, &ClipPlane::frustum()).is_empty());
+    }
 
-            let (discard, color) = shader.fragment(model, bc_screen, color);
+    #[test]
+    fn clip_triangle_cuts_corners() {
+        // One corner past `x = w`, one behind the near plane
+        let pts = [Vector4::new(-0.5, 0., 0., 1.), Vector4::new(3., 0., 0., 1.), Vector4::new(0., 0.5, 0., -1.)];
+        let [_, right, ..] = ClipPlane::frustum();
+        let planes = [right, ClipPlane::near(0.1)];
+        let polygon = clip_triangle(&pts, &planes);
+        assert_eq!(polygon.len(), 4);
+        for (p, bar) in &polygon {
+            assert!((bar.sum() - 1.).abs() < 1e-6);
+            let interpolated = pts[0] * bar.x + pts[1] * bar.y + pts[2] * bar.z;
+            assert!((interpolated - p).norm() < 1e-5, "{p} is not at {bar}");
+            assert!(planes.iter().all(|plane| plane.distance(p) > -1e-5), "{p} is outside");
+        }
+        assert!(polygon.iter().any(|(p, _)| right.distance(p).abs() < 1e-5));
+        assert!(polygon.iter().any(|(p, _)| ClipPlane::near(0.1).distance(p).abs() < 1e-5));
+    }
 
-            if !discard {
-                zbuffer[(p.x + p.y * imwidth) as usize] = frag_depth;
-                image.put_pixel(p.x as u32, p.y as u32, color);
+    #[test]
+    fn far_corners_keep_the_shape_of_triangles() {
+        // A corner far beyond the guard band, whose clamping would bend the
+        // edges through the screen
+        const SIZE: u32 = 100;
+        let pts = [(-1e5, -5e4), (0.9, 0.8), (0.5, -0.9)];
+        let positions = pts.map(|(x, y)| Vector4::new(x, y, 0., 1.));
+        let stencil = coverage(&mesh(&positions, &[[0, 1, 2]]), SIZE, 64);
+
+        let screen = pts.map(|(x, y)| ((x as f64 + 1.) * SIZE as f64 / 2., (y as f64 + 1.) * SIZE as f64 / 2.));
+        for y in 0..SIZE {
+            for x in 0..SIZE {
+                let (px, py) = (x as f64 + 0.5, y as f64 + 0.5);
+                // Signed distances in pixels to the edges
+                let distances = [0, 1, 2].map(|i| {
+                    let ((ax, ay), (bx, by)) = (screen[i], screen[(i + 1) % 3]);
+                    ((bx - ax) * (py - ay) - (by - ay) * (px - ax)) / (bx - ax).hypot(by - ay)
+                });
+                // Samples this close to an edge are left to the fill rule
+                if distances.iter().any(|d| d.abs() < 0.05) {
+                    continue;
+                }
+                let inside = distances.iter().all(|&d| d > 0.) || distances.iter().all(|&d| d < 0.);
+                assert_eq!(stencil.get(x, y) == 1, inside, "pixel ({x}, {y})");
             }
+        }
+    }
+
+    #[test]
+    fn fragments_get_screen_and_perspective_barycentrics() {
+        const SIZE: u32 = 16;
+        // Lower left half of the screen, its lower right corner farther away
+        let w = Vector3::new(1., 4., 1.);
+        let positions = [Vector4::new(-1., -1., w.x, 1.), Vector4::new(1., -1., w.y, 1.), Vector4::new(-1., 1., w.z, 1.)];
+        let mut model = mesh(&positions, &[[0, 1, 2]]);
+        for (i, vertex) in model.vertices.iter_mut().enumerate() {
+            vertex.uv = Vector3::ith(i, 1.);
+        }
+        let mut framebuffer = Framebuffer::new(SIZE, SIZE);
+        framebuffer.add_color("screen", ColorFormat::Rgba32F);
+        framebuffer.add_color("perspective", ColorFormat::Rgba32F);
+        draw(&model, &BarShader, &(), &Pipeline::new(viewport(0., 0., SIZE as f32, SIZE as f32)), &mut framebuffer);
 
-            p.y += 1.;
+        let (screen, perspective) = (framebuffer.color("screen").unwrap(), framebuffer.color("perspective").unwrap());
+        for y in 0..SIZE {
+            for x in 0..SIZE {
+                // Pixel centers strictly inside the triangle
+                let (u, v) = ((x as f32 + 0.5) / SIZE as f32, (y as f32 + 0.5) / SIZE as f32);
+                if u + v >= 1. {
+                    continue;
+                }
+                let bar = screen.get(x, y);
+                let expected = Vector3::new(1. - u - v, u, v);
+                assert!((Vector3::new(bar.r, bar.g, bar.b) - expected).norm() < 1e-4, "pixel ({x}, {y}): {bar:?}");
+                // The far corner weighs less once corrected for perspective
+                let corrected = perspective.get(x, y);
+                let expected = expected.component_div(&w) / expected.component_div(&w).sum();
+                assert!((Vector3::new(corrected.r, corrected.g, corrected.b) - expected).norm() < 1e-4, "pixel ({x}, {y}): {corrected:?}");
+            }
         }
-        p.x += 1.;
     }
 }
@@ -0,0 +1,343 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, prelude::*};
+use std::path::Path;
+use std::sync::Arc;
+use nalgebra::{Matrix4, SMatrix, SVector, UnitQuaternion, Vector3};
+use crate::bounds::{Aabb, BoundingSphere};
+use crate::framebuffer::Framebuffer;
+use crate::material::TextureCache;
+use crate::model::{Location, Model, ModelError, parse_float_vector, tokenize};
+use crate::my_gl::{DrawStats, Pipeline};
+use crate::shaders::{ShaderParams, ShaderProgram};
+
+/// Placement of a node in the space of its parent: scaled, then rotated,
+/// then translated.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Transform {
+    pub translation: SVector<f32, 3>,
+    pub rotation: UnitQuaternion<f32>,
+    pub scale: SVector<f32, 3>,
+}
+
+impl Default for Transform {
+    fn default() -> Self {
+        Transform {
+            translation: Vector3::zeros(),
+            rotation: UnitQuaternion::identity(),
+            scale: Vector3::new(1., 1., 1.),
+        }
+    }
+}
+
+impl Transform {
+    /// Takes the space of the node to the space of its parent.
+    pub fn matrix(&self) -> SMatrix<f32, 4, 4> {
+        Matrix4::new_translation(&self.translation)
+            * self.rotation.to_homogeneous()
+            * Matrix4::new_nonuniform_scaling(&self.scale)
+    }
+}
+
+/// Index of a node in [`Scene::nodes`].
+pub type NodeId = usize;
+
+/// A node of a [`Scene`]: an instance of a mesh, or a group moving its
+/// children together.
+#[derive(Clone)]
+pub struct Node {
+    pub name: String,
+    /// Node whose space `transform` places this one in, world space if `None`
+    pub parent: Option<NodeId>,
+    pub transform: Transform,
+    /// Mesh drawn at the node, shared with the other instances of it
+    pub mesh: Option<Arc<Model>>,
+}
+
+/// Meshes placed in the world by a hierarchy of transforms.
+///
+/// Parents come before their children in `nodes`, so that world transforms
+/// are found in one pass.
+#[derive(Clone, Default)]
+pub struct Scene {
+    pub nodes: Vec<Node>,
+}
+
+impl Scene {
+    /// Scene of one instance of `model`, placed in the world as is.
+    pub fn single(model: Model) -> Self {
+        let mut scene = Scene::default();
+        scene.add(Node { name: "model".to_string(), parent: None, transform: Transform::default(), mesh: Some(Arc::new(model)) });
+        scene
+    }
+
+    /// Adds `node`, whose parent must already be in the scene.
+    pub fn add(&mut self, node: Node) -> NodeId {
+        assert!(node.parent.is_none_or(|parent| parent < self.nodes.len()), "parent of `{}` not in the scene", node.name);
+        self.nodes.push(node);
+        self.nodes.len() - 1
+    }
+
+    /// Last node named `name`.
+    pub fn find(&self, name: &str) -> Option<NodeId> {
+        self.nodes.iter().rposition(|node| node.name == name)
+    }
+
+    /// Matrices taking the space of every node to world space, indexed like
+    /// `nodes`.
+    pub fn world_transforms(&self) -> Vec<SMatrix<f32, 4, 4>> {
+        let mut worlds: Vec<SMatrix<f32, 4, 4>> = Vec::with_capacity(self.nodes.len());
+        for node in &self.nodes {
+            let local = node.transform.matrix();
+            worlds.push(node.parent.map_or(local, |parent| worlds[parent] * local));
+        }
+        worlds
+    }
+
+    /// Every mesh of the scene with the matrix taking it to world space.
+    pub fn instances(&self) -> impl Iterator<Item = (&Model, SMatrix<f32, 4, 4>)> + '_ {
+        self.nodes.iter().zip(self.world_transforms())
+            .filter_map(|(node, world)| Some((node.mesh.as_deref()?, world)))
+    }
+
+    /// World-space bounding box of the meshes, or `None` without any vertex.
+    pub fn aabb(&self) -> Option<Aabb> {
+        self.instances()
+            .filter_map(|(model, world)| Some(model.aabb()?.transformed(&world)))
+            .reduce(|a, b| a.union(&b))
+    }
+
+    /// World-space sphere holding the bounding box of the meshes.
+    pub fn bounding_sphere(&self) -> Option<BoundingSphere> {
+        BoundingSphere::from_points(self.aabb()?.corners())
+    }
+
+    /// Draws every instance with the shader `create` makes for it, and sums
+    /// what became of their faces.
+    ///
+    /// `params` are those of world space: each instance gets them with its
+    /// model matrix after `params.transformation`, `params.modelview` and
+    /// `params.model`, and the same lights. Instances for which `create` gives
+    /// no shader, e.g. flattened by a singular matrix, are skipped.
+    pub fn draw(
+        &self,
+        params: &ShaderParams,
+        create: impl Fn(&ShaderParams) -> Option<Box<dyn ShaderProgram>>,
+        pipeline: &Pipeline,
+        framebuffer: &mut Framebuffer,
+    ) -> DrawStats {
+        let mut stats = DrawStats::default();
+        for (model, world) in self.instances() {
+            let local = ShaderParams {
+                transformation: params.transformation * world,
+                modelview: params.modelview * world,
+                model: params.model * world,
+                lights: params.lights.clone(),
+                base_color: params.base_color,
+            };
+            let Some(program) = create(&local) else {
+                continue;
+            };
+            stats += program.draw(model, pipeline, framebuffer);
+        }
+        stats
+    }
+
+    /// Reads a scene file.
+    ///
+    /// Like OBJ files, it has one statement per line, with `#` starting a
+    /// comment; paths are relative to the directory of the file:
+    ///
+    /// ```text
+    /// mesh head african_head.obj       # loads a mesh under a new name, once for
+    ///                                  # every instance
+    /// diffuse african_head_diffuse.tga # replaces the maps of the last mesh,
+    /// normal head_tangent_nm.png       # as `Model::override_textures`; the
+    /// specular head_exponent.png       # normal map is in tangent space
+    ///
+    /// node left head                   # an instance of `head`
+    /// translate -1 0 0                 # the transform of the last node
+    /// rotate 0 30 0                    # degrees about x, then y, then z
+    /// node small head
+    /// parent left                      # placed relative to `left`
+    /// translate 0 1.2 0
+    /// scale 0.3                        # or `scale x y z`
+    /// node group                       # no mesh, moves its children
+    /// ```
+    ///
+    /// Texture maps are shared by every mesh using them.
+    pub fn from_file(path: &str) -> Result<Self, ModelError> {
+        let file = File::open(path).map_err(|source| ModelError::Io { path: path.to_string(), source })?;
+        let dir = Path::new(path).parent().unwrap_or(Path::new(""));
+        let resolve = |file: &str| dir.join(file).to_string_lossy().into_owned();
+
+        let mut textures = TextureCache::default();
+        let mut meshes: HashMap<String, Model> = HashMap::new();
+        let mut last_mesh: Option<String> = None;
+        // Meshes are shared once read to the end, as their maps may change until then
+        let mut nodes: Vec<(Node, Option<String>)> = Vec::new();
+
+        for (n, line) in BufReader::new(file).lines().enumerate() {
+            let l = line.map_err(|source| ModelError::Io { path: path.to_string(), source })?;
+            let at = Location { path: path.to_string(), line: n + 1, column: 1 };
+            let words = tokenize(&l);
+            let Some(&(_, keyword)) = words.first() else {
+                continue;
+            };
+            let argument = |i: usize, expected: usize| match words.get(i) {
+                Some(&(column, word)) => Ok((Location { column, ..at.clone() }, word)),
+                None => Err(ModelError::MissingValue { at: at.clone(), keyword: keyword.to_string(), expected }),
+            };
+
+            match keyword {
+                "mesh" => {
+                    let (name_at, name) = argument(1, 2)?;
+                    let (_, file) = argument(2, 2)?;
+                    if meshes.contains_key(name) {
+                        return Err(ModelError::Redefinition { at: name_at, kind: "mesh", name: name.to_string() });
+                    }
+                    meshes.insert(name.to_string(), Model::from_file_with_textures(&resolve(file), &mut textures)?);
+                    last_mesh = Some(name.to_string());
+                }
+                "diffuse" | "normal" | "specular" => {
+                    let Some(model) = last_mesh.as_ref().and_then(|name| meshes.get_mut(name)) else {
+                        return Err(ModelError::MisplacedStatement { at, keyword: keyword.to_string(), after: "mesh" });
+                    };
+                    let file = resolve(argument(1, 1)?.1);
+                    match keyword {
+                        "diffuse" => model.override_textures(Some(&file), None, None, &mut textures)?,
+                        "normal" => model.override_textures(None, Some(&file), None, &mut textures)?,
+                        _ => model.override_textures(None, None, Some(&file), &mut textures)?,
+                    }
+                }
+                "node" => {
+                    let (_, name) = argument(1, 1)?;
+                    let mesh = match words.get(2) {
+                        Some(_) => {
+                            let (at, mesh) = argument(2, 2)?;
+                            if !meshes.contains_key(mesh) {
+                                return Err(ModelError::UnknownReference { at, kind: "mesh", name: mesh.to_string() });
+                            }
+                            Some(mesh.to_string())
+                        }
+                        None => None,
+                    };
+                    let node = Node { name: name.to_string(), parent: None, transform: Transform::default(), mesh: None };
+                    nodes.push((node, mesh));
+                }
+                "parent" => {
+                    let Some(last) = nodes.len().checked_sub(1) else {
+                        return Err(ModelError::MisplacedStatement { at, keyword: keyword.to_string(), after: "node" });
+                    };
+                    let (at, name) = argument(1, 1)?;
+                    let Some(parent) = nodes[..last].iter().rposition(|(node, _)| node.name == name) else {
+                        return Err(ModelError::UnknownReference { at, kind: "node", name: name.to_string() });
+                    };
+                    nodes[last].0.parent = Some(parent);
+                }
+                "translate" | "rotate" | "scale" => {
+                    let Some((node, _)) = nodes.last_mut() else {
+                        return Err(ModelError::MisplacedStatement { at, keyword: keyword.to_string(), after: "node" });
+                    };
+                    let transform = &mut node.transform;
+                    match keyword {
+                        "translate" => transform.translation = parse_float_vector(&at, &words, 3)?,
+                        "rotate" => {
+                            let angles = parse_float_vector(&at, &words, 3)?.map(f32::to_radians);
+                            transform.rotation = UnitQuaternion::from_euler_angles(angles.x, angles.y, angles.z);
+                        }
+                        _ => {
+                            if words.len() != 2 && words.len() != 4 {
+                                let (keyword, found) = (keyword.to_string(), words.len() - 1);
+                                return Err(ModelError::WrongValueCount { at, keyword, expected: "1 or 3", found });
+                            }
+                            let scale = parse_float_vector(&at, &words, 1)?;
+                            let scale = if words.len() == 2 { Vector3::repeat(scale.x) } else { scale };
+                            // A zero scale flattens the node, which then has no inverse to light it with
+                            if scale.iter().any(|s| *s == 0. || !s.is_finite()) {
+                                return Err(ModelError::SingularTransform { at });
+                            }
+                            transform.scale = scale;
+                        }
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        let meshes: HashMap<String, Arc<Model>> = meshes.into_iter().map(|(name, model)| (name, Arc::new(model))).collect();
+        let mut scene = Scene::default();
+        for (node, mesh) in nodes {
+            scene.add(Node { mesh: mesh.and_then(|name| meshes.get(&name).cloned()), ..node });
+        }
+        Ok(scene)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::tests::write_files;
+
+    const TRIANGLE: &str = "v 0 0 0\nv 1 0 0\nv 0 1 0\nf 1 2 3\n";
+
+    /// Loads the scene file `contents` as `test.scene`, next to a triangle
+    /// mesh `triangle.obj`.
+    fn load(test: &str, contents: &str) -> Result<Scene, ModelError> {
+        let dir = write_files(test, &[("triangle.obj", TRIANGLE), ("test.scene", contents)]);
+        Scene::from_file(&dir.join("test.scene").to_string_lossy())
+    }
+
+    #[test]
+    fn mesh_names_are_unique() {
+        let result = load("redefined_mesh", "mesh a triangle.obj\nmesh  a triangle.obj\n");
+        let Err(ModelError::Redefinition { at, kind, name }) = result else {
+            panic!("mesh a redefined");
+        };
+        assert_eq!((at.line, at.column, kind, name.as_str()), (2, 7, "mesh", "a"));
+    }
+
+    #[test]
+    fn scene_errors_are_located() {
+        let error = |test: &str, contents: &str| match load(test, contents) {
+            Ok(_) => panic!("{contents:?} loaded"),
+            Err(error) => error,
+        };
+        let ModelError::UnknownReference { at, kind: "mesh", name } = error("unknown_mesh", "node a  b\n") else {
+            panic!("mesh b found");
+        };
+        assert_eq!((at.line, at.column, name.as_str()), (1, 9, "b"));
+        let ModelError::UnknownReference { at, kind: "node", name } = error("unknown_node", "node a\nparent b\nnode b\n") else {
+            panic!("node b found");
+        };
+        assert_eq!((at.line, at.column, name.as_str()), (2, 8, "b"));
+
+        for (contents, keyword, after) in [
+            ("diffuse d.png\n", "diffuse", "mesh"),
+            ("mesh a triangle.obj\ntranslate 1 0 0\n", "translate", "node"),
+            ("parent a\n", "parent", "node"),
+        ] {
+            let ModelError::MisplacedStatement { at, keyword: k, after: a } = error("misplaced", contents) else {
+                panic!("{contents:?}");
+            };
+            assert_eq!((at.line, k.as_str(), a), (contents.lines().count(), keyword, after));
+        }
+
+        let ModelError::MalformedNumber { at, token } = error("scene_number", "node a\n\ntranslate 1 x 0\n") else {
+            panic!("x parsed");
+        };
+        assert_eq!((at.line, at.column, token.as_str()), (3, 13, "x"));
+        assert!(matches!(error("missing_file", "mesh a\n"), ModelError::MissingValue { expected: 2, .. }));
+        assert!(matches!(
+            error("scale_count", "node a\nscale 1 2\n"),
+            ModelError::WrongValueCount { found: 2, .. },
+        ));
+        assert!(matches!(error("zero_scale", "node a\nscale 1 0 1\n"), ModelError::SingularTransform { .. }));
+
+        // Errors of the meshes point into their own files
+        let ModelError::Io { path, .. } = error("missing_mesh", "mesh a missing.obj\n") else {
+            panic!("missing.obj loaded");
+        };
+        assert!(path.ends_with("missing.obj"), "{path}");
+    }
+}
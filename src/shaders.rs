@@ -1,205 +1,489 @@
-use std::process;
+use nalgebra::{SVector, Vector4, SMatrix};
+use crate::color::Color;
+use crate::light::Light;
+use crate::framebuffer::{Framebuffer, FragmentOutputs};
+use crate::material::Material;
+use crate::model::{Model, Vertex};
+use crate::my_gl::{self, DrawStats, Pipeline, m2v, proj4_3, v2m};
+use crate::texture::TexCoords;
 
-use image::Rgb;
-use nalgebra::{SVector, Vector3, SMatrix, Matrix3, Matrix4, Matrix4x3};
-use crate::model::Model;
-use crate::{my_gl::{proj4_3, m2v, v2m, m2v_floor}, LIGHT_DIR};
 
+/// Values a vertex shader outputs for the rasterizer to interpolate over a
+/// face. Implemented for floats, vectors and tuples of them.
+pub trait Varyings: Copy + Send + Sync {
+    /// Sum of `values` weighted by `bar`, whose components add up to 1.
+    fn interpolate(values: &[Self; 3], bar: &SVector<f32, 3>) -> Self;
+}
 
-pub trait IShader {
-    fn init() -> Self where Self: Sized;  // Because we want IShader to be an object type
-    fn vertex(&mut self,
-        model: &Model,
-        transformation: SMatrix<f32, 4, 4>,
-        iface: usize,
-        nthvert: usize,) -> SVector<f32, 4>;
-    fn fragment(&self, model: &Model, bar: SVector<f32, 3>, base_color: Rgb<u8>) -> (bool, Rgb<u8>); 
+impl Varyings for () {
+    fn interpolate(_values: &[Self; 3], _bar: &SVector<f32, 3>) -> Self {}
 }
 
-pub struct GouraudShader {
-    varying_intensity: SVector<f32, 3>,
+impl Varyings for f32 {
+    fn interpolate(values: &[Self; 3], bar: &SVector<f32, 3>) -> Self {
+        values[0] * bar.x + values[1] * bar.y + values[2] * bar.z
+    }
 }
 
-impl IShader for GouraudShader {
-    fn init() -> Self {
-        return GouraudShader{
-            varying_intensity: Vector3::new(0., 0., 0.)
-        }
+impl<const D: usize> Varyings for SVector<f32, D> {
+    fn interpolate(values: &[Self; 3], bar: &SVector<f32, 3>) -> Self {
+        values[0] * bar.x + values[1] * bar.y + values[2] * bar.z
+    }
+}
+
+impl Varyings for Color {
+    fn interpolate(values: &[Self; 3], bar: &SVector<f32, 3>) -> Self {
+        values[0] * bar.x + values[1] * bar.y + values[2] * bar.z
+    }
+}
+
+impl<A: Varyings, B: Varyings> Varyings for (A, B) {
+    fn interpolate(values: &[Self; 3], bar: &SVector<f32, 3>) -> Self {
+        (A::interpolate(&values.map(|v| v.0), bar), B::interpolate(&values.map(|v| v.1), bar))
+    }
+}
+
+impl<A: Varyings, B: Varyings, C: Varyings> Varyings for (A, B, C) {
+    fn interpolate(values: &[Self; 3], bar: &SVector<f32, 3>) -> Self {
+        (
+            A::interpolate(&values.map(|v| v.0), bar),
+            B::interpolate(&values.map(|v| v.1), bar),
+            C::interpolate(&values.map(|v| v.2), bar),
+        )
+    }
+}
+
+/// Input of a fragment shader.
+pub struct Fragment<'a, V> {
+    /// Window coordinates: pixel center and depth
+    pub position: SVector<f32, 3>,
+    /// Index of the face in the model
+    pub face: usize,
+    /// Material of the face
+    pub material: &'a Material,
+    /// Barycentric coordinates of the pixel center in the face, linear in
+    /// screen space, to interpolate without perspective correction
+    pub bar_screen: SVector<f32, 3>,
+    /// Vertex shader outputs, interpolated with perspective correction
+    pub varyings: V,
+    /// `varyings` at the center of the next pixel right, for derivatives
+    pub varyings_dx: V,
+    /// `varyings` at the center of the next pixel up, for derivatives
+    pub varyings_dy: V,
+}
+
+impl<V> Fragment<'_, V> {
+    /// Change of the varying `f` selects to the next pixel right and up.
+    pub fn derivatives<const D: usize>(&self, f: impl Fn(&V) -> SVector<f32, D>) -> (SVector<f32, D>, SVector<f32, D>) {
+        let v = f(&self.varyings);
+        (f(&self.varyings_dx) - v, f(&self.varyings_dy) - v)
+    }
+}
+
+/// A programmable shader, run by [`my_gl::draw`].
+///
+/// `vertex` is run once per model vertex and returns its clip-space position
+/// with the varyings to interpolate over the faces around it. `fragment` is
+/// run for every pixel a face covers, with the interpolated varyings, and
+/// writes its outputs, one per color attachment of the framebuffer. Both get
+/// the uniforms of the draw call.
+pub trait IShader {
+    /// Values shared by every vertex and fragment of a draw call
+    type Uniforms;
+    /// Vertex shader outputs interpolated for the fragment shader
+    type Varyings: Varyings;
+
+    fn vertex(&self, uniforms: &Self::Uniforms, vertex: &Vertex) -> (SVector<f32, 4>, Self::Varyings);
+
+    /// Writes the outputs of `fragment`, or returns `false` to discard it.
+    fn fragment(&self, uniforms: &Self::Uniforms, fragment: &Fragment<Self::Varyings>, out: &mut FragmentOutputs) -> bool;
+}
+
+/// Shader that only writes depth, for depth prepasses and shadow maps. Its
+/// uniform takes the model to clip space.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DepthShader;
+
+impl IShader for DepthShader {
+    type Uniforms = SMatrix<f32, 4, 4>;
+    type Varyings = ();
+
+    fn vertex(&self, transformation: &SMatrix<f32, 4, 4>, vertex: &Vertex) -> (SVector<f32, 4>, ()) {
+        (transformation * v2m(vertex.position), ())
     }
 
-    fn vertex(
-        &mut self,
-        model: &Model,
-        transformation: SMatrix<f32, 4, 4>,
-        iface: usize,
-        nthvert: usize,
-    ) -> SVector<f32, 4> {
-        self.varying_intensity[nthvert] = f32::max(0., model.uv_normal(iface, nthvert).dot(&LIGHT_DIR));
-        let mut gl_vertex: SMatrix<f32, 4, 1> = v2m(model.verts[model.faces[iface][nthvert] as usize]);
-        gl_vertex = transformation * gl_vertex;
-        return m2v(gl_vertex);
+    fn fragment(&self, _uniforms: &SMatrix<f32, 4, 4>, _fragment: &Fragment<()>, _out: &mut FragmentOutputs) -> bool {
+        true
     }
+}
 
-    fn fragment(&self, model: &Model, bar: SVector<f32, 3>, base_color: Rgb<u8>) -> (bool, Rgb<u8>) {
-        let intensity: f32 = self.varying_intensity.dot(&bar);
-        let color: Rgb<u8> = Rgb([
-            (base_color.0[0] as f32 * intensity) as u8,
-            (base_color.0[1] as f32 * intensity) as u8,
-            (base_color.0[2] as f32 * intensity) as u8
-        ]);
-        return (false, color)
+/// Light reaching every surface whatever the light, `5 / 255` once encoded to
+/// sRGB, scaled by the ambient color of the material.
+const AMBIENT: f32 = 0.0015;
+
+/// Uniforms of the shaders lighting a flat base color.
+#[derive(Clone, Debug)]
+pub struct LightingUniforms {
+    /// Takes the model to clip space
+    pub transformation: SMatrix<f32, 4, 4>,
+    /// Model matrix, taking the model to world space
+    pub model: SMatrix<f32, 4, 4>,
+    /// Inverse transpose of the linear part of `model`, taking normals to
+    /// world space
+    pub model_it: SMatrix<f32, 3, 3>,
+    /// Lights of the scene, in world space
+    pub lights: Vec<Light>,
+    /// Color lit, in linear light
+    pub base_color: Color,
+}
+
+impl LightingUniforms {
+    /// `None` when `model` is singular.
+    pub fn new(transformation: SMatrix<f32, 4, 4>, model: SMatrix<f32, 4, 4>, lights: Vec<Light>, base_color: Color) -> Option<Self> {
+        Some(LightingUniforms {
+            transformation,
+            model,
+            model_it: model.fixed_slice::<3, 3>(0, 0).try_inverse()?.transpose(),
+            lights,
+            base_color,
+        })
     }
 }
 
-pub struct CartoonShader {
-    varying_intensity: SVector<f32, 3>,
+/// World-space position of the model-space point `p`.
+fn to_world(model: &SMatrix<f32, 4, 4>, p: SVector<f32, 3>) -> SVector<f32, 3> {
+    proj4_3(m2v(model * v2m(p)))
+}
+
+/// Per-vertex diffuse lighting of a flat base color, without shadows, over
+/// the ambient light scaled by the ambient color of the material.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct GouraudShader;
+
+impl IShader for GouraudShader {
+    type Uniforms = LightingUniforms;
+    /// Light reaching the surface
+    type Varyings = Color;
+
+    fn vertex(&self, uniforms: &LightingUniforms, vertex: &Vertex) -> (SVector<f32, 4>, Color) {
+        let position = to_world(&uniforms.model, vertex.position);
+        let normal = (uniforms.model_it * vertex.normal).normalize();
+        let light = uniforms.lights.iter()
+            .filter_map(|light| light.incident(position))
+            .map(|(l, radiance)| radiance * f32::max(0., normal.dot(&l)))
+            .sum();
+        (uniforms.transformation * v2m(vertex.position), light)
+    }
+
+    fn fragment(&self, uniforms: &LightingUniforms, fragment: &Fragment<Color>, out: &mut FragmentOutputs) -> bool {
+        let ambient = Color::from(fragment.material.ambient) * AMBIENT;
+        out.set(0, (uniforms.base_color * (ambient + fragment.varyings)).with_alpha(uniforms.base_color.a));
+        true
+    }
 }
 
+/// Gouraud lighting quantized into a few bands.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CartoonShader;
+
 impl IShader for CartoonShader {
-    fn init() -> Self {
-        return CartoonShader {
-            varying_intensity: Vector3::new(0., 0., 0.)
-        }
+    type Uniforms = LightingUniforms;
+    /// Light reaching the surface
+    type Varyings = Color;
+
+    fn vertex(&self, uniforms: &LightingUniforms, vertex: &Vertex) -> (SVector<f32, 4>, Color) {
+        GouraudShader.vertex(uniforms, vertex)
     }
 
-    // Is there a way to take this implementation from GoraudShader?
-    fn vertex(
-        &mut self,
-        model: &Model,
-        transformation: SMatrix<f32, 4, 4>,
-        iface: usize,
-        nthvert: usize,
-    ) -> SVector<f32, 4> {
-        self.varying_intensity[nthvert] = f32::max(0., model.uv_normal(iface, nthvert).dot(&LIGHT_DIR));
-        let mut gl_vertex: SMatrix<f32, 4, 1> = v2m(model.verts[model.faces[iface][nthvert] as usize]);
-        gl_vertex = transformation * gl_vertex;
-        return m2v(gl_vertex);
-    }
-
-    fn fragment(&self, model: &Model, bar: SVector<f32, 3>, base_color: Rgb<u8>) -> (bool, Rgb<u8>) {
-        let mut intensity: f32 = self.varying_intensity.dot(&bar);
-        intensity = match intensity {
-            x if (0.85..1.00).contains(&x) => 1.,
+    fn fragment(&self, uniforms: &LightingUniforms, fragment: &Fragment<Color>, out: &mut FragmentOutputs) -> bool {
+        // Bands of brightness, keeping the hue of the light
+        let luminance = fragment.varyings.luminance();
+        let intensity = match luminance {
+            x if x >= 0.85 => 1.,
             x if (0.60..0.85).contains(&x) => 0.80,
             x if (0.45..0.60).contains(&x) => 0.60,
             x if (0.30..0.45).contains(&x) => 0.45,
             x if (0.15..0.30).contains(&x) => 0.30,
             _ => 0.,
         };
+        let light = if luminance > 0. { fragment.varyings * (intensity / luminance) } else { Color::BLACK };
+        out.set(0, (uniforms.base_color * light).with_alpha(uniforms.base_color.a));
+        true
+    }
+}
+
+/// Uniforms of [`Shader`].
+#[derive(Clone, Debug)]
+pub struct ShaderUniforms {
+    /// Projection-modelview matrix
+    pub uniform_m: SMatrix<f32, 4, 4>,
+    /// Model matrix, taking the model to world space
+    pub uniform_model: SMatrix<f32, 4, 4>,
+    /// Inverse transpose of the linear part of the model matrix, taking
+    /// normals to world space
+    pub uniform_model_it: SMatrix<f32, 3, 3>,
+    /// Inverse transpose of the linear part of the view matrix, taking
+    /// world-space normals to view space
+    pub uniform_mit: SMatrix<f32, 3, 3>,
+    /// Homogeneous world-space position of the camera, with `w = 0` for a
+    /// camera infinitely far away
+    pub uniform_eye: SVector<f32, 4>,
+    /// Lights of the scene, in world space
+    pub lights: Vec<Light>,
+}
 
-        let color: Rgb<u8> = Rgb([
-            (base_color.0[0] as f32 * intensity) as u8,
-            (base_color.0[1] as f32 * intensity) as u8,
-            (base_color.0[2] as f32 * intensity) as u8
-        ]);
-        return (false, color)
+impl ShaderUniforms {
+    /// `uniform_m` is the projection-modelview matrix, `modelview` its part
+    /// taking the model to view space and `model` the part of that taking
+    /// the model to world space; `None` when any of them is singular.
+    pub fn new(
+        uniform_m: SMatrix<f32, 4, 4>,
+        modelview: SMatrix<f32, 4, 4>,
+        model: SMatrix<f32, 4, 4>,
+        lights: Vec<Light>,
+    ) -> Option<Self> {
+        let inv_matrix = uniform_m.try_inverse()?;
+        let view = modelview * model.try_inverse()?;
+        Some(ShaderUniforms {
+            uniform_m,
+            uniform_model: model,
+            uniform_model_it: model.fixed_slice::<3, 3>(0, 0).try_inverse()?.transpose(),
+            uniform_mit: view.fixed_slice::<3, 3>(0, 0).try_inverse()?.transpose(),
+            // The point every line of sight goes through, towards the near side
+            uniform_eye: model * (inv_matrix * Vector4::z()),
+            lights,
+        })
     }
 
+    /// Unit direction from the world-space point `p` towards the camera.
+    fn view_dir(&self, p: SVector<f32, 3>) -> SVector<f32, 3> {
+        let eye = self.uniform_eye;
+        if eye.w == 0. {
+            proj4_3(eye).normalize()
+        } else {
+            (proj4_3(eye) / eye.w - p).normalize()
+        }
+    }
 }
 
-pub struct Shader {
-    varying_uv: SMatrix<f32, 3, 3>,
-    varying_nrm: SMatrix<f32, 3, 3>,
-    varying_tri: SMatrix<f32, 4, 3>,
-    uniform_M: SMatrix<f32, 4, 4>,
-    uniform_MIT: SMatrix<f32, 4, 4>,
-    ndc_tri: SMatrix<f32, 3, 3> //Not used at all now
+/// Varyings of [`Shader`].
+#[derive(Copy, Clone, Debug)]
+pub struct ShaderVaryings {
+    position: SVector<f32, 3>,
+    uv: SVector<f32, 3>,
+    normal: SVector<f32, 3>,
+    tangent: SVector<f32, 4>,
 }
 
-impl Shader {
-    pub fn new(uniform_m: SMatrix<f32, 4, 4>) -> Self {
-        let inv_matrix = uniform_m.try_inverse().unwrap();
-        return Shader {
-            varying_uv: Matrix3::<f32>::zeros(),
-            varying_nrm: Matrix3::<f32>::zeros(),
-            varying_tri: Matrix4x3::<f32>::zeros(),
-            uniform_M: uniform_m,
-            uniform_MIT: inv_matrix.transpose(),
-            ndc_tri: Matrix3::<f32>::zeros()
+impl Varyings for ShaderVaryings {
+    fn interpolate(values: &[Self; 3], bar: &SVector<f32, 3>) -> Self {
+        ShaderVaryings {
+            position: Varyings::interpolate(&values.map(|v| v.position), bar),
+            uv: Varyings::interpolate(&values.map(|v| v.uv), bar),
+            normal: Varyings::interpolate(&values.map(|v| v.normal), bar),
+            tangent: Varyings::interpolate(&values.map(|v| v.tangent), bar),
         }
     }
+}
 
-    pub fn vertex(&mut self, model: &Model, transformation: SMatrix<f32, 4, 4>, iface: usize, nthvert: usize) -> SVector<f32, 4> {
-        self.varying_uv.set_column(nthvert, &model.uv(iface, nthvert));
-        self.varying_nrm.set_column(nthvert,
-            &proj4_3(m2v(self.uniform_MIT * v2m(model.uv_normal(iface, nthvert))))
-        );
-        let mut gl_vertex: SMatrix<f32, 4, 1> = v2m(model.verts[model.faces[iface][nthvert] as usize]);
-        gl_vertex = transformation * gl_vertex;
-        self.varying_tri.set_column(nthvert, &gl_vertex);
-        self.ndc_tri.set_column(nthvert, &proj4_3(m2v_floor(gl_vertex)));
-        return m2v_floor(gl_vertex);
-    }
 
-    pub fn fragment(&self, model: &Model, bar: SVector<f32, 3>, base_color: Rgb<u8>) -> (bool, Rgb<u8>) {
-        let bn:SMatrix<f32, 3, 1> = (self.varying_nrm * bar).normalize();
-        let uvw: SMatrix<f32, 3, 1> = self.varying_uv * bar;
+/// Textured shader with tangent-space normal mapping and specular highlights,
+/// summed over the lights of its uniforms and shadowed by their shadow maps.
+///
+/// Lighting is computed in world space and in linear light, and may exceed
+/// `1`; it is encoded, saturating, by sRGB attachments. Writes the color to
+/// output 0 and the view-space shading normal, mapped to `0..1`, to output 1,
+/// as [`crate::ssao::Ssao::compute`] takes it.
+///
+/// The dissolve of the material is only written as the alpha of the color,
+/// and fully dissolved faces are discarded; nothing is blended, so partly
+/// dissolved faces hide what is behind them like opaque ones.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Shader;
 
-        let A: SMatrix<f32, 3, 3> = SMatrix::from_rows(&[
-            (self.ndc_tri.column(1) - self.ndc_tri.column(0)).transpose(),
-            (self.ndc_tri.column(2) - self.ndc_tri.column(0)).transpose(),
-            bn.transpose()
-        ]);
+/// Tangent frame mapping tangent-space normals around the normal `bn`.
+fn tangent_basis(bn: SVector<f32, 3>, tangent: SVector<f32, 4>) -> SMatrix<f32, 3, 3> {
+    let t: SVector<f32, 3> = proj4_3(tangent);
+    let i = (t - bn * bn.dot(&t)).try_normalize(f32::EPSILON).unwrap_or(t);
+    let j = bn.cross(&i) * tangent.w;
+    SMatrix::from_columns(&[i, j, bn])
+}
 
-        let AI: SMatrix<f32, 3, 3> = A.try_inverse().unwrap();
+impl IShader for Shader {
+    type Uniforms = ShaderUniforms;
+    type Varyings = ShaderVaryings;
 
-        let i: SVector<f32, 3> = AI * Vector3::new(
-            self.varying_uv[(0, 1)] - self.varying_uv[(0, 0)],
-            self.varying_uv[(0, 2)] - self.varying_uv[(0, 0)],
-            0.,
-        );
+    fn vertex(&self, uniforms: &ShaderUniforms, vertex: &Vertex) -> (SVector<f32, 4>, ShaderVaryings) {
+        // Tangents follow the surface, unlike normals
+        let tangent = uniforms.uniform_model.fixed_slice::<3, 3>(0, 0) * proj4_3(vertex.tangent);
+        let varyings = ShaderVaryings {
+            position: to_world(&uniforms.uniform_model, vertex.position),
+            uv: vertex.uv,
+            normal: uniforms.uniform_model_it * vertex.normal,
+            tangent: tangent.push(vertex.tangent.w),
+        };
+        (uniforms.uniform_m * v2m(vertex.position), varyings)
+    }
+
+    fn fragment(&self, uniforms: &ShaderUniforms, fragment: &Fragment<ShaderVaryings>, out: &mut FragmentOutputs) -> bool {
+        let material = fragment.material;
+        if material.dissolve <= 0. {
+            return false
+        }
+
+        let bn: SVector<f32, 3> = fragment.varyings.normal.normalize();
+        let (ddx, ddy) = fragment.derivatives(|v| v.uv.xy());
+        let uv = TexCoords { uv: fragment.varyings.uv.xy(), ddx, ddy };
 
-        let j: SVector<f32, 3> = AI * Vector3::new(
-            self.varying_uv[(1, 1)] - self.varying_uv[(1, 0)],
-            self.varying_uv[(1, 2)] - self.varying_uv[(1, 0)],
-            0.,
-        );
+        let n: SVector<f32, 3> = match material.sample_normal(&uv) {
+            Some(tangent_normal) => (tangent_basis(bn, fragment.varyings.tangent) * tangent_normal).normalize(),
+            None => bn,
+        };
+        let position: SVector<f32, 3> = fragment.varyings.position;
+        let shininess: f32 = material.sample_specular(&uv);
+        let specular: Color = Color::from(material.specular);
+        let v: SVector<f32, 3> = uniforms.view_dir(position);
 
-        let B: SMatrix<f32, 3, 3> = SMatrix::from_columns(&[
-            i.normalize(),
-            j.normalize(),
-            bn
-        ]);
+        let mut light: Color = Color::BLACK;
+        for source in &uniforms.lights {
+            let Some((l, radiance)) = source.incident(position) else {
+                continue;
+            };
+            let r: SVector<f32, 3> = (2.*n*(n.dot(&l)) - l).normalize();
 
-        let n: SVector<f32, 3> = (B * model.normal(uvw)).normalize();
-        let l: SVector<f32, 4> = m2v(self.uniform_M * v2m(LIGHT_DIR));
-        let l_norm: SVector<f32, 3> = proj4_3(l).normalize();
-        let r: SVector<f32, 3> = (2.*n*(n.dot(&l_norm)) - l_norm).normalize();
+            let spec: f32 = f32::powf(f32::max(r.dot(&v), 0.), shininess);
+            let diffuse: f32 = f32::max(0., n.dot(&l));
 
-        let spec: f32 = f32::powf(f32::max(r.z, 0.), model.specular(uvw));
-        let diffuse: f32 = f32::max(0., n.dot(&l_norm));
+            // Shadowed parts keep some of the light, as if it were bouncing around
+            let shadow: f32 = match &source.shadow {
+                Some(_) => 0.3 + 0.7 * source.visibility(position),
+                None => 1.,
+            };
+            light += radiance * (Color::gray(diffuse) + specular * spec) * shadow;
+        }
 
-        let mut color: Rgb<u8> = model.diffuse(uvw);
-        color = Rgb([
-            (5. + color.0[0] as f32 * (diffuse + 0.3 * spec)) as u8,
-            (5. + color.0[1] as f32 * (diffuse + 0.3 * spec)) as u8,
-            (5. + color.0[2] as f32 * (diffuse + 0.3 * spec)) as u8,
-        ]);
-        return (false, color)
+        let albedo: Color = material.sample_diffuse(&uv);
+        let ambient = Color::from(material.ambient) * AMBIENT;
+        out.set(0, (ambient + albedo * light).with_alpha(material.dissolve));
+        let n_view: SVector<f32, 3> = (uniforms.uniform_mit * n).normalize();
+        out.set(1, Color::rgb(n_view.x * 0.5 + 0.5, n_view.y * 0.5 + 0.5, n_view.z * 0.5 + 0.5));
+        true
     }
+}
+
+/// Inputs the shaders of a [`ShaderRegistry`] build their uniforms from.
+#[derive(Clone, Debug)]
+pub struct ShaderParams {
+    /// Projection-modelview matrix
+    pub transformation: SMatrix<f32, 4, 4>,
+    /// Modelview matrix, taking the model to view space
+    pub modelview: SMatrix<f32, 4, 4>,
+    /// Model matrix, taking the model to world space
+    pub model: SMatrix<f32, 4, 4>,
+    /// Lights of the scene, in world space, with their shadow maps
+    pub lights: Vec<Light>,
+    /// Color of the shaders that do not use the material, in linear light
+    pub base_color: Color,
+}
 
+/// A shader with the uniforms of a draw call, drawable without knowing its
+/// types; see [`BoundShader`].
+pub trait ShaderProgram: Sync {
+    /// Draws every face of `model`, see [`my_gl::draw`].
+    fn draw(&self, model: &Model, pipeline: &Pipeline, framebuffer: &mut Framebuffer) -> DrawStats;
 }
 
-pub enum AnyShader {
-    Shader(Shader)
+/// A shader bound to its uniforms.
+#[derive(Clone, Debug)]
+pub struct BoundShader<S: IShader> {
+    pub shader: S,
+    pub uniforms: S::Uniforms,
 }
 
-impl From<Shader> for AnyShader {
-    fn from(shader: Shader) -> Self {
-        AnyShader::Shader(shader)
+impl<S: IShader + Sync> ShaderProgram for BoundShader<S> where S::Uniforms: Sync {
+    fn draw(&self, model: &Model, pipeline: &Pipeline, framebuffer: &mut Framebuffer) -> DrawStats {
+        my_gl::draw(model, &self.shader, &self.uniforms, pipeline, framebuffer)
     }
 }
 
-impl AnyShader {
-    pub fn vertex(&mut self, model: &Model, transformation: SMatrix<f32, 4, 4>, iface: usize, nthvert: usize) -> SVector<f32, 4> {
-        match self {
-            AnyShader::Shader(f) => f.vertex(model, transformation, iface, nthvert),
-        }
+/// Builds a shader program for the given parameters, or `None` if they do
+/// not suit it, e.g. a singular transformation.
+pub type ShaderFactory = Box<dyn Fn(&ShaderParams) -> Option<Box<dyn ShaderProgram>> + Send + Sync>;
+
+/// Shaders selectable by name.
+///
+/// [`ShaderRegistry::default`] holds the shaders of this crate, `phong`
+/// ([`Shader`]), `gouraud` and `cartoon`; other crates can
+/// [`register`](ShaderRegistry::register) theirs.
+pub struct ShaderRegistry {
+    shaders: Vec<(String, ShaderFactory)>,
+}
+
+impl ShaderRegistry {
+    /// Registry without any shader.
+    pub fn empty() -> Self {
+        ShaderRegistry { shaders: Vec::new() }
     }
 
-    pub fn fragment(&self, model: &Model, bar: SVector<f32, 3>, base_color: Rgb<u8>) -> (bool, Rgb<u8>) {
-        match self {
-            AnyShader::Shader(f) => f.fragment(model, bar, base_color),
-        }
+    /// Adds a shader, replacing any other registered under `name`.
+    pub fn register<F>(&mut self, name: &str, factory: F)
+    where F: Fn(&ShaderParams) -> Option<Box<dyn ShaderProgram>> + Send + Sync + 'static {
+        self.shaders.retain(|(n, _)| n != name);
+        self.shaders.push((name.to_string(), Box::new(factory)));
+    }
+
+    /// Names of the registered shaders, in registration order.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.shaders.iter().map(|(name, _)| name.as_str())
+    }
+
+    /// Builds the shader registered under `name`, or `None` if there is
+    /// none or it does not accept `params`.
+    pub fn create(&self, name: &str, params: &ShaderParams) -> Option<Box<dyn ShaderProgram>> {
+        self.shaders.iter().find(|(n, _)| n == name).and_then(|(_, factory)| factory(params))
+    }
+}
+
+impl Default for ShaderRegistry {
+    fn default() -> Self {
+        let lighting = |params: &ShaderParams| {
+            LightingUniforms::new(params.transformation, params.model, params.lights.clone(), params.base_color)
+        };
+
+        let mut registry = ShaderRegistry::empty();
+        registry.register("phong", |params| {
+            let uniforms = ShaderUniforms::new(params.transformation, params.modelview, params.model, params.lights.clone())?;
+            Some(Box::new(BoundShader { shader: Shader, uniforms }))
+        });
+        registry.register("gouraud", move |params| Some(Box::new(BoundShader { shader: GouraudShader, uniforms: lighting(params)? })));
+        registry.register("cartoon", move |params| Some(Box::new(BoundShader { shader: CartoonShader, uniforms: lighting(params)? })));
+        registry
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nalgebra::{Matrix4, Vector3};
+
+    #[test]
+    fn gouraud_lights_in_world_space() {
+        // Slanted face stretched along x, its world normal no longer the
+        // model normal scaled
+        let model = Matrix4::new_nonuniform_scaling(&Vector3::new(4., 1., 1.));
+        let world_normal = Vector3::new(0.25, 1., 0.).normalize();
+        let lights = vec![Light::directional(world_normal), Light::point(Vector3::new(8., 0., 0.))];
+        let uniforms = LightingUniforms::new(SMatrix::identity(), model, lights, Color::WHITE).unwrap();
+        let vertex = Vertex {
+            position: Vector3::new(1., 0., 0.),
+            uv: Vector3::zeros(),
+            normal: Vector3::new(1., 1., 0.).normalize(),
+            tangent: Vector4::zeros(),
+        };
+        // Full light from the direction of the world normal, and the point
+        // light 4 units away along x in world space
+        let expected = 1. + world_normal.x / 17.;
+        let (_, light) = GouraudShader.vertex(&uniforms, &vertex);
+        assert!((light.r - expected).abs() < 1e-5, "{light:?}");
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,87 @@
+use std::sync::Arc;
+use nalgebra::{SVector, SMatrix, Vector3};
+use crate::bounds::BoundingSphere;
+use crate::camera::{Camera, Projection};
+use crate::depth::DepthBuffer;
+use crate::framebuffer::Framebuffer;
+use crate::my_gl::{self, m2v, v2m};
+use crate::scene::Scene;
+use crate::shaders::DepthShader;
+
+/// Depth of a scene as seen from a directional light, to tell which points
+/// the light reaches.
+#[derive(Clone, Debug)]
+pub struct ShadowMap {
+    /// Depth seen from the light, shared by the copies of the map
+    pub depth: Arc<DepthBuffer>,
+    /// Takes world space to the window coordinates of `depth`
+    pub transformation: SMatrix<f32, 4, 4>,
+    /// Depth a point may lie behind the map and still be lit, against shadow acne
+    pub bias: f32,
+    /// Half-size in texels of the percentage-closer filter; 0 takes one sample
+    pub pcf_radius: u32,
+}
+
+impl ShadowMap {
+    /// Renders the depth of every instance of `scene` from the direction
+    /// `light_dir`, with an orthographic projection framing the whole scene
+    /// to a `size` x `size` map.
+    pub fn render_scene(scene: &Scene, light_dir: SVector<f32, 3>, size: u32) -> Self {
+        let up = if light_dir.cross(&Vector3::y()).norm() < f32::EPSILON { Vector3::z() } else { Vector3::y() };
+        let sphere = scene.bounding_sphere().unwrap_or(BoundingSphere { center: Vector3::zeros(), radius: 1. });
+        let camera = Camera {
+            projection: Projection::Orthographic { height: 2. },
+            ..Camera::new(light_dir, Vector3::zeros(), up)
+        }.fit(&sphere);
+        let view_projection = camera.view_projection();
+        let viewport = my_gl::viewport(0., 0., size as f32, size as f32);
+
+        let mut framebuffer = ShadowMap::framebuffer(size);
+        let pipeline = camera.pipeline(viewport);
+        for (model, world) in scene.instances() {
+            my_gl::draw(model, &DepthShader, &(view_projection * world), &pipeline, &mut framebuffer);
+        }
+        ShadowMap::new(framebuffer, viewport * view_projection)
+    }
+
+    /// Depth-only framebuffer of a `size` x `size` map.
+    fn framebuffer(size: u32) -> Framebuffer {
+        let mut framebuffer = Framebuffer::new(size, size);
+        framebuffer.depth = Some(DepthBuffer::new(size, size));
+        framebuffer
+    }
+
+    fn new(framebuffer: Framebuffer, transformation: SMatrix<f32, 4, 4>) -> Self {
+        ShadowMap {
+            depth: Arc::new(framebuffer.depth.unwrap()),
+            transformation,
+            bias: 0.01,
+            pcf_radius: 1,
+        }
+    }
+
+    /// Fraction of the texels around the world-space point `p` that see it,
+    /// `1` when fully lit. Points outside the map are lit.
+    pub fn visibility(&self, p: SVector<f32, 3>) -> f32 {
+        let screen = m2v(self.transformation * v2m(p));
+        let depth = self.depth.window_depth(screen.z);
+        // Compares as if the point were `bias` nearer to the light
+        let biased = depth + (self.depth.near - self.depth.far).signum() * self.bias;
+
+        let (width, height) = (self.depth.width() as i64, self.depth.height() as i64);
+        let (cx, cy) = (screen.x.floor() as i64, screen.y.floor() as i64);
+        let r = self.pcf_radius as i64;
+        let (mut lit, mut total) = (0, 0);
+        for y in cy - r..=cy + r {
+            for x in cx - r..=cx + r {
+                total += 1;
+                // In shadow when what the light sees would pass the depth test over the point
+                let outside = x < 0 || y < 0 || x >= width || y >= height;
+                if outside || !self.depth.func.passes(self.depth.get(x as u32, y as u32), biased) {
+                    lit += 1;
+                }
+            }
+        }
+        lit as f32 / total as f32
+    }
+}
@@ -0,0 +1,168 @@
+use std::f32::consts::PI;
+use image::{GrayImage, ImageBuffer, Luma};
+use nalgebra::{SVector, Vector2};
+use rayon::prelude::*;
+use crate::color::Color;
+use crate::depth::DepthBuffer;
+use crate::framebuffer::ColorAttachment;
+
+/// Ambient light reaching every pixel, from `0` (occluded) to `1`.
+pub type OcclusionMap = ImageBuffer<Luma<f32>, Vec<f32>>;
+
+/// Screen-space ambient occlusion from a depth buffer.
+///
+/// Around every pixel, the depth buffer is marched along `samples` directions
+/// for `radius` pixels to find how high the horizon rises above the surface;
+/// the higher it is, the less ambient light the pixel gets. The horizon starts
+/// from the plane of the surface, given by the normals when there are some and
+/// by the depth of the neighbouring pixels otherwise.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Ssao {
+    /// Distance in pixels within which geometry occludes
+    pub radius: f32,
+    /// Number of directions marched around each pixel
+    pub samples: u32,
+    /// Pixels per unit of stored depth, to compare depths with screen distances
+    pub depth_scale: f32,
+    /// Scale of the occlusion, `1` for physically plausible
+    pub strength: f32,
+}
+
+impl Ssao {
+    /// Occlusion of every pixel of `depth`. `normals` holds view-space normals
+    /// mapped to `0..1`, as written to output 1 by [`crate::shaders::Shader`];
+    /// pixels with a zero alpha have no normal.
+    pub fn compute(&self, depth: &DepthBuffer, normals: Option<&ColorAttachment>) -> OcclusionMap {
+        let (width, height) = (depth.width(), depth.height());
+        let directions: Vec<SVector<f32, 2>> = (0..self.samples.max(1))
+            .map(|k| {
+                let angle = 2. * PI * k as f32 / self.samples.max(1) as f32;
+                Vector2::new(angle.cos(), angle.sin())
+            })
+            .collect();
+
+        let mut data = vec![1.; (width * height) as usize];
+        data.par_chunks_mut(width.max(1) as usize).enumerate().for_each(|(y, row)| {
+            for (x, ao) in row.iter_mut().enumerate() {
+                let normal = normals
+                    .map(|n| n.get(x as u32, y as u32))
+                    .filter(|n| n.a > 0.)
+                    .map(|n| SVector::<f32, 3>::new(n.r, n.g, n.b) * 2. - SVector::<f32, 3>::repeat(1.));
+                *ao = self.pixel(depth, &directions, x as u32, y as u32, normal);
+            }
+        });
+        ImageBuffer::from_raw(width, height, data).unwrap()
+    }
+
+    fn pixel(&self, depth: &DepthBuffer, directions: &[SVector<f32, 2>], x: u32, y: u32, normal: Option<SVector<f32, 3>>) -> f32 {
+        let center = depth.get(x, y);
+        if center == depth.far {
+            return 1.;
+        }
+        // Height towards the camera, in pixels, of a depth difference
+        let toward_camera = (depth.near - depth.far).signum() * self.depth_scale;
+        let steps = self.radius.max(1.).ceil() as u32;
+
+        // Slope of the surface along x and y
+        let slope = match normal {
+            Some(n) if n.z > f32::EPSILON => -Vector2::new(n.x, n.y) / n.z,
+            _ => depth_gradient(depth, x, y) * toward_camera,
+        };
+        let mut occlusion = 0.;
+        for d in directions {
+            let tangent_angle = slope.dot(d).atan();
+            let mut horizon = tangent_angle;
+            for step in 1..=steps {
+                let t = step as f32;
+                let (sx, sy) = ((x as f32 + 0.5 + d.x * t).floor(), (y as f32 + 0.5 + d.y * t).floor());
+                if sx < 0. || sy < 0. || sx >= depth.width() as f32 || sy >= depth.height() as f32 {
+                    break;
+                }
+                // The sampled pixel is off the ray: measure the horizon in the
+                // plane along `d`, discounting the rise of the surface across it
+                let offset = Vector2::new(sx - x as f32, sy - y as f32);
+                let along = offset.dot(d);
+                let h = (depth.get(sx as u32, sy as u32) - center) * toward_camera;
+                // Geometry farther than the radius, in front or behind, does not count
+                if along <= 0. || offset.norm_squared() + h * h > self.radius * self.radius {
+                    continue;
+                }
+                let rise = h - slope.dot(&(offset - d * along));
+                horizon = f32::max(horizon, (rise / along).atan());
+            }
+            occlusion += horizon.sin() - tangent_angle.sin();
+        }
+        (1. - self.strength * occlusion / directions.len() as f32).clamp(0., 1.)
+    }
+}
+
+/// Depth change per pixel at (`x`, `y`), from the neighbours on the side where
+/// it changes least, so that the edges of objects are ignored.
+fn depth_gradient(depth: &DepthBuffer, x: u32, y: u32) -> SVector<f32, 2> {
+    let center = depth.get(x, y);
+    let derivative = |before: Option<f32>, after: Option<f32>| {
+        let differences = [before.map(|d| center - d), after.map(|d| d - center)];
+        differences.into_iter().flatten().min_by(|a, b| a.abs().total_cmp(&b.abs())).unwrap_or(0.)
+    };
+    let at = |x: u32, y: u32| (x < depth.width() && y < depth.height()).then(|| depth.get(x, y)).filter(|&d| d != depth.far);
+    Vector2::new(
+        derivative(x.checked_sub(1).and_then(|x| at(x, y)), at(x + 1, y)),
+        derivative(y.checked_sub(1).and_then(|y| at(x, y)), at(x, y + 1)),
+    )
+}
+
+/// Darkens `color` by the ambient occlusion `ao`, of the same size.
+pub fn composite(color: &mut ColorAttachment, ao: &OcclusionMap) {
+    for (x, y, a) in ao.enumerate_pixels() {
+        let c = color.get(x, y);
+        color.put(x, y, c * Color::gray(a.0[0]));
+    }
+}
+
+/// 8-bit grayscale copy of `ao`, e.g. to save it.
+pub fn to_gray8(ao: &OcclusionMap) -> GrayImage {
+    ImageBuffer::from_fn(ao.width(), ao.height(), |x, y| Luma([(ao.get_pixel(x, y).0[0] * 255.).round() as u8]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIZE: u32 = 32;
+
+    fn ssao() -> Ssao {
+        Ssao { radius: 6., samples: 8, depth_scale: 100., strength: 1. }
+    }
+
+    /// Depth buffer holding `f(x, y)` at every pixel.
+    fn depth_of(f: impl Fn(f32, f32) -> f32) -> DepthBuffer {
+        let mut depth = DepthBuffer::new(SIZE, SIZE);
+        for y in 0..SIZE {
+            for x in 0..SIZE {
+                depth.update(x, y, f(x as f32, y as f32));
+            }
+        }
+        depth
+    }
+
+    #[test]
+    fn tilted_planes_are_not_occluded() {
+        let depth = depth_of(|x, y| 0.5 + 0.003 * x - 0.002 * y);
+        let ao = ssao().compute(&depth, None);
+        for (x, y, a) in ao.enumerate_pixels() {
+            assert!(a.0[0] > 0.99, "pixel ({x}, {y}): {}", a.0[0]);
+        }
+    }
+
+    #[test]
+    fn creases_are_occluded() {
+        // Valley along x = 16, its sides rising towards the camera
+        let valley = depth_of(|x, _| 0.5 - 0.004 * (x - 16.).abs());
+        // Step rising towards the camera at x = 16
+        let step = depth_of(|x, _| if x < 16. { 0.5 } else { 0.47 });
+        for depth in [valley, step] {
+            let ao = ssao().compute(&depth, None);
+            assert!(ao.get_pixel(15, 16).0[0] < 0.95, "{}", ao.get_pixel(15, 16).0[0]);
+        }
+    }
+}
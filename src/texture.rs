@@ -0,0 +1,271 @@
+use image::{ImageBuffer, RgbImage, Rgb};
+use nalgebra::{SVector, Vector2, Vector3};
+use crate::color::{ColorSpace, linear_to_srgb8, srgb8_to_linear};
+
+/// An 8-bit RGB texture map with its chain of mipmaps, each half the size of
+/// the previous one down to a single texel.
+#[derive(Clone, Debug)]
+pub struct Texture {
+    levels: Vec<RgbImage>,
+    color_space: ColorSpace,
+}
+
+impl Texture {
+    /// Texture of `image`, whose channels are encoded in `color_space`,
+    /// generating its mipmaps with a box filter over linear values.
+    pub fn new(image: RgbImage, color_space: ColorSpace) -> Self {
+        let mut levels = vec![image];
+        loop {
+            let last = levels.last().unwrap();
+            let (width, height) = last.dimensions();
+            if width <= 1 && height <= 1 {
+                break;
+            }
+            let (w, h) = ((width / 2).max(1), (height / 2).max(1));
+            let next = ImageBuffer::from_fn(w, h, |x, y| {
+                let mut sum = [0.; 3];
+                for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                    let texel = last.get_pixel((2 * x + dx).min(width - 1), (2 * y + dy).min(height - 1));
+                    for (s, &c) in sum.iter_mut().zip(&texel.0) {
+                        *s += Texture::decode(color_space, c);
+                    }
+                }
+                Rgb(sum.map(|s| Texture::encode(color_space, s / 4.)))
+            });
+            levels.push(next);
+        }
+        Texture { levels, color_space }
+    }
+
+    fn decode(color_space: ColorSpace, c: u8) -> f32 {
+        match color_space {
+            ColorSpace::Linear => c as f32 / 255.,
+            ColorSpace::Srgb => srgb8_to_linear(c),
+        }
+    }
+
+    fn encode(color_space: ColorSpace, c: f32) -> u8 {
+        match color_space {
+            ColorSpace::Linear => (c * 255.).round().clamp(0., 255.) as u8,
+            ColorSpace::Srgb => linear_to_srgb8(c),
+        }
+    }
+
+    /// Size of the full-resolution image.
+    pub fn dimensions(&self) -> (u32, u32) {
+        self.levels[0].dimensions()
+    }
+
+    /// Mipmap `level`, 0 being the full-resolution image.
+    pub fn level(&self, level: usize) -> &RgbImage {
+        &self.levels[level]
+    }
+
+    pub fn level_count(&self) -> usize {
+        self.levels.len()
+    }
+
+    pub fn color_space(&self) -> ColorSpace {
+        self.color_space
+    }
+}
+
+/// How texture coordinates outside `0..1` are brought back in.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Wrap {
+    /// Tiles the texture
+    Repeat,
+    /// Stretches the edge texels
+    Clamp,
+    /// Tiles the texture, flipping every other copy
+    Mirror,
+}
+
+impl Wrap {
+    /// Index of texel `i` of a row or column of `size` texels.
+    fn texel(self, i: i64, size: u32) -> u32 {
+        let size = size as i64;
+        let i = match self {
+            Wrap::Repeat => i.rem_euclid(size),
+            Wrap::Clamp => i.clamp(0, size - 1),
+            Wrap::Mirror => {
+                let m = i.rem_euclid(2 * size);
+                if m < size { m } else { 2 * size - 1 - m }
+            }
+        };
+        i as u32
+    }
+}
+
+/// How texels are combined into a sample.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Filter {
+    /// Nearest texel of the nearest mipmap
+    Nearest,
+    /// Weighted average of the four nearest texels of the nearest mipmap
+    Bilinear,
+    /// Bilinear samples of the two nearest mipmaps, blended
+    Trilinear,
+}
+
+/// Texture coordinates of a fragment, with their change to the next pixel
+/// right (`ddx`) and up (`ddy`) that selects the mipmap.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct TexCoords {
+    pub uv: SVector<f32, 2>,
+    pub ddx: SVector<f32, 2>,
+    pub ddy: SVector<f32, 2>,
+}
+
+impl TexCoords {
+    /// Coordinates sampling the full-resolution image.
+    pub fn new(uv: SVector<f32, 2>) -> Self {
+        TexCoords { uv, ddx: Vector2::zeros(), ddy: Vector2::zeros() }
+    }
+}
+
+/// Filtering and wrapping of texture lookups. Sampling never panics, whatever
+/// the coordinates.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Sampler {
+    pub wrap_u: Wrap,
+    pub wrap_v: Wrap,
+    pub filter: Filter,
+}
+
+impl Default for Sampler {
+    /// Repeating trilinear sampler.
+    fn default() -> Self {
+        Sampler { wrap_u: Wrap::Repeat, wrap_v: Wrap::Repeat, filter: Filter::Trilinear }
+    }
+}
+
+impl Sampler {
+    /// Color of `texture` at `coords`, with channels in `0..1`: linear light
+    /// for sRGB textures, decoded before filtering. `v` grows upwards, from
+    /// the last row of the image to the first.
+    pub fn sample(&self, texture: &Texture, coords: &TexCoords) -> SVector<f32, 3> {
+        let lod = Sampler::lod(texture, coords).clamp(0., (texture.level_count() - 1) as f32);
+        match self.filter {
+            Filter::Nearest => self.nearest(texture, lod.round() as usize, coords.uv),
+            Filter::Bilinear => self.bilinear(texture, lod.round() as usize, coords.uv),
+            Filter::Trilinear => {
+                let (level, t) = (lod.floor() as usize, lod.fract());
+                let fine = self.bilinear(texture, level, coords.uv);
+                if t == 0. {
+                    return fine;
+                }
+                fine * (1. - t) + self.bilinear(texture, level + 1, coords.uv) * t
+            }
+        }
+    }
+
+    /// Mipmap level of detail: log2 of the texels covered by a pixel.
+    pub fn lod(texture: &Texture, coords: &TexCoords) -> f32 {
+        let (width, height) = texture.dimensions();
+        let size = Vector2::new(width as f32, height as f32);
+        let rho = f32::max(coords.ddx.component_mul(&size).norm(), coords.ddy.component_mul(&size).norm());
+        if rho.is_finite() && rho > 0. { rho.log2() } else { 0. }
+    }
+
+    /// Texel coordinates of `uv` in `image`, in texels from its top left corner.
+    fn texel_position(image: &RgbImage, uv: SVector<f32, 2>) -> (f32, f32) {
+        // Keep far-away and invalid coordinates where integer texel indices hold them
+        let bound = |c: f32| if c.is_finite() { c.clamp(-1e6, 1e6) } else { 0. };
+        (bound(uv.x) * image.width() as f32, (1. - bound(uv.y)) * image.height() as f32)
+    }
+
+    fn texel(&self, texture: &Texture, level: usize, x: i64, y: i64) -> SVector<f32, 3> {
+        let image = texture.level(level);
+        let texel = image.get_pixel(self.wrap_u.texel(x, image.width()), self.wrap_v.texel(y, image.height()));
+        let [r, g, b] = texel.0.map(|c| Texture::decode(texture.color_space, c));
+        Vector3::new(r, g, b)
+    }
+
+    fn nearest(&self, texture: &Texture, level: usize, uv: SVector<f32, 2>) -> SVector<f32, 3> {
+        let (x, y) = Sampler::texel_position(texture.level(level), uv);
+        self.texel(texture, level, x.floor() as i64, y.floor() as i64)
+    }
+
+    fn bilinear(&self, texture: &Texture, level: usize, uv: SVector<f32, 2>) -> SVector<f32, 3> {
+        // Texel centers are at half-integer positions
+        let (x, y) = Sampler::texel_position(texture.level(level), uv);
+        let (x, y) = (x - 0.5, y - 0.5);
+        let (x0, y0) = (x.floor(), y.floor());
+        let (tx, ty) = (x - x0, y - y0);
+        let (x0, y0) = (x0 as i64, y0 as i64);
+        let top = self.texel(texture, level, x0, y0) * (1. - tx) + self.texel(texture, level, x0 + 1, y0) * tx;
+        let bottom = self.texel(texture, level, x0, y0 + 1) * (1. - tx) + self.texel(texture, level, x0 + 1, y0 + 1) * tx;
+        top * (1. - ty) + bottom * ty
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 4 x 4 linear texture whose red channel tells the texel: `16 x + 64 y`
+    /// from the top left.
+    fn grid() -> Texture {
+        Texture::new(ImageBuffer::from_fn(4, 4, |x, y| Rgb([(16 * x + 64 * y) as u8, 0, 0])), ColorSpace::Linear)
+    }
+
+    /// Texel (x, y) of `grid` sampled at `uv` with the nearest filter.
+    fn nearest_texel(wrap: Wrap, uv: (f32, f32)) -> (u32, u32) {
+        let sampler = Sampler { wrap_u: wrap, wrap_v: wrap, filter: Filter::Nearest };
+        let r = (sampler.sample(&grid(), &TexCoords::new(Vector2::new(uv.0, uv.1))).x * 255.).round() as u32;
+        (r % 64 / 16, r / 64)
+    }
+
+    #[test]
+    fn repeat_tiles_coordinates() {
+        // u across the texture from x = 0, with v in the top row
+        for (u, x) in [(0., 0), (0.6, 2), (1., 0), (-0.25, 3), (1.25, 1), (-3.9, 0)] {
+            assert_eq!(nearest_texel(Wrap::Repeat, (u, 0.9)), (x, 0), "u = {u}");
+        }
+        // v up the texture from the bottom row, y = 3; v = 0 is also v = 1
+        for (v, y) in [(0., 0), (0.6, 1), (1., 0), (-0.25, 1), (1.25, 3), (0.1, 3)] {
+            assert_eq!(nearest_texel(Wrap::Repeat, (0.1, v)), (0, y), "v = {v}");
+        }
+    }
+
+    #[test]
+    fn clamp_stretches_edges() {
+        for (u, x) in [(0., 0), (0.6, 2), (1., 3), (-0.25, 0), (1.25, 3), (-1e9, 0)] {
+            assert_eq!(nearest_texel(Wrap::Clamp, (u, 0.9)), (x, 0), "u = {u}");
+        }
+        for (v, y) in [(0., 3), (0.6, 1), (1., 0), (-0.25, 3), (1.25, 0), (f32::NAN, 3)] {
+            assert_eq!(nearest_texel(Wrap::Clamp, (0.1, v)), (0, y), "v = {v}");
+        }
+    }
+
+    #[test]
+    fn mipmaps_follow_the_texels_per_pixel() {
+        // Black and white checkerboard, whose mipmaps are all mid-gray
+        let texture = Texture::new(
+            ImageBuffer::from_fn(8, 8, |x, y| Rgb([if (x + y) % 2 == 0 { 255 } else { 0 }; 3])),
+            ColorSpace::Linear,
+        );
+        assert_eq!(texture.level_count(), 4);
+        let coords = |texels: f32| TexCoords {
+            uv: Vector2::new(0.5 / 8., 1. - 0.5 / 8.),
+            ddx: Vector2::new(texels / 8., 0.),
+            ddy: Vector2::zeros(),
+        };
+        for (texels, lod) in [(0., 0.), (0.5, -1.), (1., 0.), (2., 1.), (4., 2.), (1e3, 1e3f32.log2())] {
+            assert!((Sampler::lod(&texture, &coords(texels)) - lod).abs() < 1e-5, "{texels} texels");
+        }
+
+        let gray = 128. / 255.;
+        let sample = |filter, texels| Sampler { filter, ..Sampler::default() }.sample(&texture, &coords(texels)).x;
+        // The top left texel is white, also when magnified
+        assert_eq!(sample(Filter::Nearest, 1.), 1.);
+        assert_eq!(sample(Filter::Nearest, 0.5), 1.);
+        assert_eq!(sample(Filter::Nearest, 2.), gray);
+        // Past the last level, the 1 x 1 one
+        assert_eq!(sample(Filter::Nearest, 1e3), gray);
+        assert_eq!(sample(Filter::Bilinear, 4.), gray);
+        // Halfway between the white texel and the gray level
+        assert!((sample(Filter::Trilinear, 2f32.sqrt()) - (1. + gray) / 2.).abs() < 1e-5);
+    }
+}